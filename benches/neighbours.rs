@@ -0,0 +1,57 @@
+//! The KD-tree against the hash grid: indexing 10k points and asking for every point's
+//! neighbours, the way a tick does, from a sparse flock to a very crowded one.
+//!
+//! `cargo bench --bench neighbours`. The density is the mean number of neighbours a point has
+use bevy::math::Vec2;
+use boids::neighbours::{KdTreeIndex, NeighbourIndex, SpatialHashGrid};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+
+const POINTS: usize = 10_000;
+const RADIUS: f32 = 35.;
+
+// Points spread evenly over a square just big enough for each to have about `density` others
+// within RADIUS
+fn points(density: f32) -> Vec<Vec2> {
+    let side = (POINTS as f32 * PI * RADIUS * RADIUS / density).sqrt();
+    let mut rng = SmallRng::seed_from_u64(1);
+    (0..POINTS)
+        .map(|_| Vec2::new(rng.gen_range(0.0..side), rng.gen_range(0.0..side)))
+        .collect()
+}
+
+fn tick(index: &mut dyn NeighbourIndex, points: &[Vec2], found: &mut Vec<usize>) -> usize {
+    index.rebuild(points, RADIUS);
+    let mut total = 0;
+    for &point in points {
+        found.clear();
+        index.within(point, RADIUS, found);
+        total += found.len();
+    }
+    total
+}
+
+fn neighbours(c: &mut Criterion) {
+    let mut group = c.benchmark_group("neighbours");
+    group.sample_size(20);
+    for density in [2., 10., 50., 200.] {
+        let points = points(density);
+        let mut found = vec![];
+        let mut kd_tree = KdTreeIndex::default();
+        group.bench_with_input(
+            BenchmarkId::new("kd_tree", density),
+            &points,
+            |b, points| b.iter(|| tick(&mut kd_tree, points, &mut found)),
+        );
+        let mut grid = SpatialHashGrid::default();
+        group.bench_with_input(BenchmarkId::new("grid", density), &points, |b, points| {
+            b.iter(|| tick(&mut grid, points, &mut found))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, neighbours);
+criterion_main!(benches);
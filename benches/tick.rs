@@ -0,0 +1,52 @@
+//! How long one whole simulation tick takes at a few flock sizes.
+//!
+//! `cargo bench --bench tick`. To keep up with 60 Hz a tick has to fit in about 16.7 ms, the
+//! flock is spread over a world big enough to keep it as dense as the default one
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use boids::sim::{BoidsSimPlugin, TICK_HZ};
+use boids::{SimBounds, SimSeed, Values};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::time::Duration;
+
+const DEFAULT_FLOCK: f32 = 1500.;
+
+fn flock(boids: i32) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        // Every update is exactly one tick
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / TICK_HZ,
+        )))
+        .add_plugins(BoidsSimPlugin)
+        .insert_resource(SimSeed(1))
+        .insert_resource(Values {
+            boid_count: boids,
+            ..default()
+        })
+        .insert_resource(SimBounds(
+            SimBounds::default().0 * (boids as f32 / DEFAULT_FLOCK).sqrt(),
+        ));
+    app.finish();
+    app.cleanup();
+    // Spawning, and a few ticks for the flock to start flying around
+    for _ in 0..10 {
+        app.update();
+    }
+    app
+}
+
+fn tick(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tick");
+    group.sample_size(20);
+    for boids in [1_500, 10_000, 50_000] {
+        let mut app = flock(boids);
+        group.bench_with_input(BenchmarkId::from_parameter(boids), &boids, |b, _| {
+            b.iter(|| app.update())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tick);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Runs the flock without a window, camera or GPU and prints where it ended up.
+//!
+//! `cargo run --example headless [seed]`, the same seed always prints the same thing
+use bevy::app::{AppExit, ScheduleRunnerPlugin};
+use bevy::prelude::*;
+use boids::boid::{SpatialEntity, Velocity};
+use boids::sim::{BoidsSimPlugin, SimSet};
+use boids::{CursorPosition, SimSeed};
+use std::time::Duration;
+
+const TICKS: u32 = 600;
+
+fn main() {
+    let seed = std::env::args()
+        .nth(1)
+        .map(|seed| seed.parse().expect("seed should be a number"))
+        .map_or_else(SimSeed::default, SimSeed);
+
+    App::new()
+        .add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::ZERO)))
+        .add_plugins(BoidsSimPlugin)
+        .insert_resource(seed)
+        // Give the flock something to chase, there is no mouse to do it
+        .insert_resource(CursorPosition(Vec2::ZERO))
+        .add_systems(FixedUpdate, exit_after_ticks.after(SimSet))
+        .run();
+}
+
+fn exit_after_ticks(
+    mut ticks: Local<u32>,
+    boids: Query<(&Transform, &Velocity), With<SpatialEntity>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    *ticks += 1;
+    if *ticks != TICKS {
+        return;
+    }
+
+    let count = boids.iter().len() as f32;
+    let center = boids
+        .iter()
+        .map(|(t, _)| t.translation.truncate())
+        .sum::<Vec2>()
+        / count;
+    let speed = boids.iter().map(|(_, v)| v.0.length()).sum::<f32>() / count;
+    println!("{TICKS} ticks: {count} boids, center of mass {center}, mean speed {speed}");
+    exit.send(AppExit::Success);
+}
@@ -0,0 +1,104 @@
+use crate::boid::{Neighbors, SpatialEntity, Velocity};
+use crate::metrics::polarization;
+use crate::sim::FlockTiming;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Line, Plot, PlotPoints};
+use std::collections::VecDeque;
+
+/// Seconds of history the plots show
+const WINDOW_SECS: f64 = 10.0;
+
+/// Rolling history of everything the analytics window plots, one sample per frame
+#[derive(Default)]
+pub struct Analytics {
+    fps: Series,
+    flock_ms: Series,
+    boids: Series,
+    polarization: Series,
+    neighbors: Series,
+}
+
+#[derive(Default)]
+struct Series(VecDeque<[f64; 2]>);
+
+impl Series {
+    fn push(&mut self, time: f64, value: f64) {
+        self.0.push_back([time, value]);
+        while self.0.front().is_some_and(|[t, _]| *t < time - WINDOW_SECS) {
+            self.0.pop_front();
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.0.iter().map(|[_, v]| v).sum::<f64>() / self.0.len().max(1) as f64
+    }
+
+    fn latest(&self) -> f64 {
+        self.0.back().map_or(0., |[_, v]| *v)
+    }
+}
+
+// Samples the flock every frame and plots the last few seconds of it. Sampling is a single pass
+// over the boids, cheap next to a tick
+pub fn analytics_system(
+    mut egui_context: EguiContexts,
+    mut analytics: Local<Analytics>,
+    time: Res<Time<Real>>,
+    timing: Option<Res<FlockTiming>>,
+    boids: Query<(&Velocity, Option<&Neighbors>), With<SpatialEntity>>,
+) {
+    let now = time.elapsed_seconds_f64();
+    let delta = time.delta_seconds_f64();
+    if delta > 0. {
+        analytics.fps.push(now, 1. / delta);
+    }
+    // Replays don't flock
+    if let Some(timing) = timing {
+        analytics.flock_ms.push(now, timing.0.as_secs_f64() * 1000.);
+    }
+    let count = boids.iter().len();
+    let neighbors: usize = boids.iter().filter_map(|(_, n)| n).map(|n| n.0).sum();
+    analytics.boids.push(now, count as f64);
+    analytics.polarization.push(
+        now,
+        polarization(boids.iter().map(|(velocity, _)| velocity.0)) as f64,
+    );
+    analytics
+        .neighbors
+        .push(now, neighbors as f64 / count.max(1) as f64);
+
+    egui::Window::new("Analytics")
+        .resizable(true)
+        .collapsible(true)
+        .default_open(false)
+        .default_pos(egui::pos2(10.0, 400.0))
+        .show(egui_context.ctx_mut(), |ui| {
+            plot(ui, "FPS", &analytics.fps, None);
+            plot(ui, "Flocking (ms)", &analytics.flock_ms, None);
+            plot(ui, "Boids", &analytics.boids, None);
+            plot(ui, "Polarization", &analytics.polarization, Some(1.0));
+            plot(ui, "Mean neighbours", &analytics.neighbors, None);
+        });
+}
+
+fn plot(ui: &mut egui::Ui, name: &str, series: &Series, max: Option<f64>) {
+    ui.label(format!(
+        "{name}: {:.2} (mean {:.2})",
+        series.latest(),
+        series.mean()
+    ));
+    let mut plot = Plot::new(name)
+        .height(70.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .show_x(false)
+        .include_y(0.0);
+    if let Some(max) = max {
+        plot = plot.include_y(max);
+    }
+    plot.show(ui, |plot_ui| {
+        plot_ui.line(Line::new(PlotPoints::from_iter(series.0.iter().copied())));
+    });
+}
@@ -1,26 +1,107 @@
+use crate::evil::{Evil, Neglect};
+use crate::neighbours::{NeighbourIndex, Neighbours};
+use crate::obstacle::{obstacle_avoidance, Obstacle};
+use crate::perch::Perched;
+use crate::predator::Predator;
+use crate::sim::FlockTiming;
+use crate::store::BoidStore;
+use crate::CursorPosition;
+use crate::SimBounds;
+use crate::SimRng;
+use crate::Topology;
 use crate::Values;
-use crate::BOUNDS;
+use crate::MARGIN;
 use bevy::math::Vec2;
 use bevy::math::Vec3;
-use bevy::math::*;
 use bevy::prelude::*;
-use bevy::sprite::MaterialMesh2dBundle;
-use bevy::sprite::Mesh2dHandle;
 use bevy::tasks::ComputeTaskPool;
-use bevy_egui::egui::epaint::color;
+use bevy::utils::Instant;
 use bevy_spatial::kdtree::KDTree2;
 use bevy_spatial::SpatialAccess;
-use halton::Sequence;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
+/// In units per second
 #[derive(Component)]
-pub struct Velocity(Vec2);
+pub struct Velocity(pub Vec2);
+
+/// Everything the boid was steered by last tick, for integrators that need it. In units per
+/// second squared
+#[derive(Component, Default)]
+pub struct Acceleration(pub Vec2);
+
+/// How `movement_system` steps a boid forward by one tick of `dt` seconds
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Moves with the old velocity, then speeds up. Simplest, and drifts the most
+    Euler,
+    /// Speeds up, then moves with the new velocity
+    #[default]
+    SemiImplicitEuler,
+    /// Velocity Verlet, moves along the curve this tick's acceleration makes instead of a straight
+    /// line, and averages it with last tick's for the new velocity
+    Verlet,
+}
 
-#[derive(Component)]
+/// How much a neighbour counts towards one of the flocking rules, by how far away it is. Each rule
+/// reaches out to its own range: the protected range for separation, the visual range for
+/// alignment and cohesion. The smooth ones fade out to exactly nothing at the edge of it, so
+/// neighbours drifting in and out don't make the boid twitch
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kernel {
+    /// Everything in range counts the same. Alignment and cohesion only count what's outside the
+    /// protected range, leaving whatever is inside it to separation
+    #[default]
+    Uniform,
+    /// Falls off in a straight line from the boid to the edge of the range
+    Linear,
+    /// Falls off with the square of the distance, strongest up close
+    InverseSquare,
+    /// A bell curve, about flat up close and gone well before the edge
+    Gaussian,
+    /// The `boid_topological_neighbors` nearest in range count the same and nobody else does,
+    /// however crowded it gets. Starlings keep track of about 7
+    Topological,
+}
+
+impl Kernel {
+    /**
+     * @param dist_sq: f32 - Distance to the neighbour, squared
+     * @param rank: usize - How many neighbours are closer to the boid than this one
+     * @param range_sq: f32 - The rule's range, squared
+     * @param values: &Values - The values resource
+     * @return f32 - How much the neighbour counts, from 0 to 1
+     * @description The kernel's weight for a neighbour
+     *
+     */
+    pub fn weight(self, dist_sq: f32, rank: usize, range_sq: f32, values: &Values) -> f32 {
+        if dist_sq >= range_sq {
+            return 0.0;
+        }
+        let x_sq = dist_sq / range_sq;
+        // Shifted and scaled so it's 1 on top of the boid and 0 at the edge of the range
+        let falloff = |f: fn(f32) -> f32| (f(x_sq) - f(1.0)) / (f(0.0) - f(1.0));
+        match self {
+            Kernel::Uniform => 1.0,
+            Kernel::Linear => 1.0 - x_sq.sqrt(),
+            Kernel::InverseSquare => falloff(|x_sq| 1.0 / (1.0 + 16.0 * x_sq)),
+            Kernel::Gaussian => falloff(|x_sq| (-4.5 * x_sq).exp()),
+            Kernel::Topological => {
+                if rank < values.boid_topological_neighbors {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Component, Default)]
 pub struct SpatialEntity;
 
-#[derive(Component)]
-pub struct SimpleColor(Vec3); // Stored as a vec3 cause it's lighter than a Color object (really???)
+#[derive(Component, PartialEq)]
+pub struct SimpleColor(pub Vec3); // Stored as a vec3 cause it's lighter than a Color object (really???)
 
 impl Default for SimpleColor {
     fn default() -> Self {
@@ -29,406 +110,823 @@ impl Default for SimpleColor {
 }
 
 impl SimpleColor {
-    pub fn random() -> Self {
-        let mut rng = rand::thread_rng();
+    pub fn random(rng: &mut impl Rng) -> Self {
         SimpleColor((360. * rng.gen::<f32>(), rng.gen(), 0.7).into())
     }
 }
+
+/// The color a boid was born with, it drifts back towards this whenever it's alone in color mode
+#[derive(Component)]
+pub struct StartColor(pub Vec3);
+
+/// How many boids this boid could see last tick
+#[derive(Component, Default, PartialEq)]
+pub struct Neighbors(pub usize);
+
+/// Runs while a boid can't see anyone else, and resets as soon as it can. A boid that stays alone
+/// for the whole `boid_lonely_time` gets a new random color
+#[derive(Component)]
+pub struct Loneliness(pub Timer);
+
+/// Boids that flock: flying and not evil. They're the ones with a neighbour count that's up to date
+pub type Flocking = (With<SpatialEntity>, Without<Perched>, Without<Evil>);
+
+/// Everything the simulation needs to know about a boid, the mesh and material are added on top
+/// of this by the render plugin when there is something to draw to
 #[derive(Bundle)]
 pub struct BoidBundle {
-    mesh: MaterialMesh2dBundle<ColorMaterial>,
+    transform: Transform,
     velocity: Velocity,
-    start_color: SimpleColor,
+    acceleration: Acceleration,
+    color: SimpleColor,
+    start_color: StartColor,
+    neighbors: Neighbors,
+    loneliness: Loneliness,
+    neglect: Neglect,
+    marker: SpatialEntity,
 }
 
-#[derive(Event)]
-pub struct DvEvent(Entity, Vec2);
+impl BoidBundle {
+    /// A boid at `position` with a random heading and color drawn from `rng`
+    pub fn random(rng: &mut impl Rng, position: Vec2, values: &Values) -> Self {
+        let velocity = Velocity(
+            Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)) * values.boid_speed,
+        );
 
-#[derive(Event)]
-pub struct ColorEvent(Entity, Vec3);
+        let color = SimpleColor::random(rng);
 
-impl Default for BoidBundle {
-    fn default() -> Self {
-        let mut rng = rand::thread_rng();
         Self {
-            mesh: Default::default(),
-            velocity: Velocity(Vec2::default()),
-            start_color: SimpleColor::random(),
+            transform: Transform::from_translation(position.extend(0.0))
+                .with_scale(Vec3::splat(values.boid_size)),
+            velocity,
+            acceleration: Acceleration::default(),
+            start_color: StartColor(color.0),
+            color,
+            neighbors: Neighbors::default(),
+            loneliness: Loneliness(Timer::from_seconds(
+                values.boid_lonely_time,
+                TimerMode::Once,
+            )),
+            neglect: Neglect(Timer::from_seconds(values.boid_evil_time, TimerMode::Once)),
+            marker: SpatialEntity,
         }
     }
 }
 
+/// How many points of the Halton sequence have been handed out to boids so far, so boids spawned
+/// later keep filling in the gaps between the ones already there
+#[derive(Resource, Default)]
+pub struct HaltonIndex(pub usize);
+
+/// Most boids spawned or despawned in a single tick when `boid_count` changes, so big jumps fade
+/// in and out instead of landing all at once
+pub const POPULATION_STEP: usize = 25;
+
+// The next spawn point from the Halton sequence, spread out evenly over the bounds
+fn next_spawn_point(index: &mut HaltonIndex, bounds: &SimBounds) -> Vec2 {
+    index.0 += 1;
+    let area = bounds.0 - Vec2::splat(2.0 * MARGIN);
+    let x = halton::number(2, index.0) as f32;
+    let y = halton::number(3, index.0) as f32;
+    Vec2::new(x * area.x - area.x / 2.0, y * area.y - area.y / 2.0)
+}
+
 pub fn boid_setup(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rng: ResMut<SimRng>,
+    mut index: ResMut<HaltonIndex>,
+    bounds: Res<SimBounds>,
     values: Res<Values>,
 ) {
-    commands.spawn(Camera2dBundle::default());
-    let mut rng = rand::thread_rng();
-    let seq = halton::Sequence::new(2)
-        .zip(Sequence::new(3))
-        .zip(1..values.boid_count);
-
-    let mut test: bool = false;
+    for _ in 0..values.boid_count.max(0) {
+        let position = next_spawn_point(&mut index, &bounds);
+        commands.spawn(BoidBundle::random(&mut rng.0, position, &values));
+    }
+}
 
-    for ((x, y), _) in seq {
-        let spawn_x = (x as f32 * BOUNDS.x) - BOUNDS.x / 2.0;
-        let spawn_y = (y as f32 * BOUNDS.y) - BOUNDS.y / 2.0;
-        let mut transform =
-            Transform::from_xyz(spawn_x, spawn_y, 0.0).with_scale(Vec3::splat(values.boid_size)); // Fixed typo here
-        transform.rotate_z(0.0);
+// Keeps the number of boids in line with `boid_count` while the app is running, spawning new ones
+// on the Halton sequence or despawning the newest ones, POPULATION_STEP at a time
+pub fn population_system(
+    mut commands: Commands,
+    boids: Query<Entity, With<SpatialEntity>>,
+    mut rng: ResMut<SimRng>,
+    mut index: ResMut<HaltonIndex>,
+    bounds: Res<SimBounds>,
+    values: Res<Values>,
+) {
+    let target = values.boid_count.max(0) as usize;
+    let current = boids.iter().len();
 
-        let velocity = Velocity(
-            Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)) * values.boid_speed,
-        );
+    for _ in current..target.min(current + POPULATION_STEP) {
+        let position = next_spawn_point(&mut index, &bounds);
+        commands.spawn(BoidBundle::random(&mut rng.0, position, &values));
+    }
 
-        let start = SimpleColor::random();
-        let color = Color::hsl(start.0.x, start.0.y, start.0.z);
-        let magic: BoidBundle = BoidBundle {
-            mesh: MaterialMesh2dBundle {
-                mesh: Mesh2dHandle(meshes.add(Circle { radius: 4.0 })),
-                //material: materials.add(Color::hsl(360. * rng.gen::<f32>(), rng.gen(), 0.7)),
-                material: materials.add(color),
-                transform,
-                ..default()
-            },
-            velocity,
-            start_color: start,
-        };
-        commands.spawn((magic, SpatialEntity));
+    if current > target {
+        let mut boids = boids.iter().collect::<Vec<_>>();
+        // Newest first, entity order is stable so this picks the same boids every run
+        boids.sort_unstable();
+        for boid in boids
+            .into_iter()
+            .rev()
+            .take((current - target).min(POPULATION_STEP))
+        {
+            commands.entity(boid).despawn();
+        }
     }
 }
 
+// A neighbour as (distance squared, slot in the store, vector to it)
+type Nearby = (f32, usize, Vec2);
+
 /**
-* @param kdtree: KDTree2<SpatialEntity> - The KDTree of all boids
-* @param boid_query: Query<(Entity, &Velocity, &Transform), With<SpatialEntity>> - Query of all
-* boids
-* @param camera: Query<(&Camera, &GlobalTransform)> - Query of the camera
-* @param window: Query<&Window> - Query of the window
-* @param boid: &Entity - The entity of the boid
-* @param t0: &&Transform - The transform of the boid
+* @param index: &dyn NeighbourIndex - Every flying boid by slot in the store
+* @param store: &BoidStore - Every flying boid, neighbours are read from its front buffer
+* @param predators: KDTree2<Predator> - The KDTree of all predators
+* @param obstacles: &[(&Transform, &Obstacle)] - Every obstacle and where it is
+* @param cursor: Option<&CursorPosition> - World position of the cursor, if there is one
+* @param slot: usize - Where the boid is in the store
+* @param found: &mut Vec<usize> - Scratch space for the slots the index finds, reused from boid to boid
+* @param nearby: &mut Vec<Nearby> - Scratch space for the neighbours, reused from boid to boid
 * @param values: &Res<Values> - The values resource
-* @return Vec2 - The delta velocity
-* @description Get the delta velocity for a boid, this is where all the real logic of the boids and
+* @param topology: Topology - Whether distances wrap around the edges of the world
+* @param dt: f32 - Length of the tick in seconds
+* @return (Vec2, Vec3, usize) - The acceleration, the new color and how many flockmates it can see
+* @description Get the acceleration for a boid, this is where all the real logic of the boids and
 * stuff takes place
 *
 */
+#[allow(clippy::too_many_arguments)]
 fn get_dv(
-    kdtree: &Res<KDTree2<SpatialEntity>>,
-    boid_query: &Query<
-        (
-            Entity,
-            &Velocity,
-            &Transform,
-            &Handle<ColorMaterial>,
-            &SimpleColor,
-        ),
-        With<SpatialEntity>,
-    >,
-    camera: &Query<(&Camera, &GlobalTransform)>,
-    window: &Query<&Window>,
-    boid: &Entity,
-    t0: &&Transform,
+    index: &dyn NeighbourIndex,
+    store: &BoidStore,
+    predators: &Res<KDTree2<Predator>>,
+    obstacles: &[(&Transform, &Obstacle)],
+    cursor: Option<&CursorPosition>,
+    slot: usize,
+    found: &mut Vec<usize>,
+    nearby: &mut Vec<Nearby>,
     values: &Res<Values>,
-) -> (Vec2, Vec3) {
+    topology: Topology,
+    dt: f32,
+) -> (Vec2, Vec3, usize) {
     let mut dv = Vec2::default();
     let mut vec_away = Vec2::default();
     let mut avg_position = Vec2::default();
     let mut avg_velocity = Vec2::default();
+    let mut vec_repel = Vec2::default();
+    let mut vec_flee = Vec2::default();
     let mut neighboring_boids = 0;
     let mut close_boids = 0;
-    let mut total_hue = 0.0;
+    let mut total_separation = 0.0;
+    let mut total_alignment = 0.0;
+    let mut total_cohesion = 0.0;
+    // Flockmates in view so far
+    let mut seen = 0;
+    let mut evil_boids = 0;
+    // Every flockmate that counted for anything, once each however many rules it counted for.
+    // Hunters don't keep anyone company
+    let mut accepted = 0;
+    let mut total_hue = Vec2::default();
     let mut total_saturation = 0.0;
 
-    let (_, _, _, _, start_color) = boid_query.get(*boid).unwrap();
-    let mut final_color = start_color.0;
+    let state = &store.front;
+    let v0 = state.velocities[slot];
+    let color = state.colors[slot];
+    let start_color = store.start_colors[slot];
+    let mut final_color = color;
+
+    // Sum the neighbours up in the order the index found them, which is the same every run, so
+    // float rounding and with it the whole trajectory is too. They only need sorting nearest first
+    // (ties broken by slot) when there are too many of them or a kernel goes by rank. On a torus
+    // boids near an edge also look for neighbours from their images on the other side of it
+    let position = state.positions[slot];
+    found.clear();
+    let mut images = 0;
+    for image in topology.images(position, values.boid_vis_range) {
+        index.within(image, values.boid_vis_range, found);
+        images += 1;
+    }
+    nearby.clear();
+    nearby.extend(
+        found
+            .iter()
+            .filter(|&&other_slot| other_slot != slot)
+            .map(|&other_slot| {
+                let vec_to = topology.offset(position, state.positions[other_slot]);
+                (vec_to.length_squared(), other_slot, vec_to)
+            })
+            .filter(|(dist_sq, ..)| *dist_sq <= values.vis_range_sq),
+    );
+    // Near a corner the same boid can turn up around more than one image
+    if images > 1 {
+        nearby.sort_unstable_by_key(|(_, other_slot, _)| *other_slot);
+        nearby.dedup_by_key(|(_, other_slot, _)| *other_slot);
+    }
+    let ranked = [
+        values.separation_kernel,
+        values.alignment_kernel,
+        values.cohesion_kernel,
+    ]
+    .contains(&Kernel::Topological);
+    if ranked || nearby.len() > values.max_neighbors {
+        nearby.sort_unstable_by_key(|(dist_sq, other_slot, _)| (dist_sq.to_bits(), *other_slot));
+        nearby.truncate(values.max_neighbors);
+    }
 
-    for (_, entity) in kdtree.k_nearest_neighbour(t0.translation.xy(), values.max_neighbors) {
-        let Ok((other, v1, t1, _, other_color)) = boid_query.get(entity.unwrap()) else {
-            continue;
+    let heading = v0.try_normalize();
+    for &(dist_sq, other_slot, vec_to) in nearby.iter() {
+        let v1 = state.velocities[other_slot];
+        let other_color = state.colors[other_slot];
+        let evil = store.evil[other_slot];
+        // How far off the way the boid is flying the other one is, as the cosine of the angle: 1
+        // dead ahead and -1 right behind. Something sitting right on top of it counts as ahead,
+        // and a boid that isn't going anywhere (just taken off with no minimum speed) sees all the
+        // way around
+        let cos = match heading {
+            Some(heading) if dist_sq > 0.0 => (heading.dot(vec_to) / dist_sq.sqrt()).clamp(-1., 1.),
+            _ => 1.0,
         };
-
-        if *boid == other {
+        // Nothing behind the boid gets seen at all, not even hunters
+        if cos < values.blind_spot_cos {
             continue;
         }
 
-        let vec_to = (t1.translation - t0.translation).xy();
-        let dist_sq = vec_to.length_squared();
+        // Hunters get noticed anywhere outside the blind spot, and nobody flocks with them
+        if evil {
+            vec_flee -= vec_to;
+            evil_boids += 1;
+            continue;
+        }
 
-        if dist_sq > values.vis_range_sq {
+        // The field of view is the full cone, so it reaches half of it to either side
+        if cos < values.fov_cos {
             continue;
         }
+        // Neighbours count for less the further out to the side they are, down to
+        // boid_peripheral_weight at the edge of the field of view
+        let half_fov = values.boid_fov / 2.0;
+        let peripheral = if values.boid_peripheral_weight != 1.0 && half_fov > 0.0 {
+            lerp(1.0, values.boid_peripheral_weight, cos.acos() / half_fov)
+        } else {
+            1.0
+        };
 
-        if let Some(vec_to_norm) = vec_to.try_normalize() {
-            if t0
-                .rotation
-                .angle_between(Quat::from_rotation_arc_2d(Vec2::X, vec_to_norm))
-                > values.boid_fov
-            {
-                continue;
+        // Every rule weighs the neighbour with its own kernel. Uniform alignment and cohesion leave
+        // the protected range to separation, like the rules always have
+        let rank = seen;
+        seen += 1;
+        let flocking = |kernel: Kernel| {
+            if kernel == Kernel::Uniform && dist_sq < values.prot_range_sq {
+                0.0
+            } else {
+                kernel.weight(dist_sq, rank, values.vis_range_sq, values)
             }
+        };
+        let separation =
+            values
+                .separation_kernel
+                .weight(dist_sq, rank, values.prot_range_sq, values);
+        let alignment = flocking(values.alignment_kernel);
+        let cohesion = flocking(values.cohesion_kernel);
+        if separation > 0.0 || alignment > 0.0 || cohesion > 0.0 {
+            accepted += 1;
         }
 
-        if dist_sq < values.prot_range_sq {
-            vec_away -= vec_to;
+        if separation > 0.0 {
+            vec_away -= vec_to * separation * peripheral;
+            total_separation += separation;
             close_boids += 1;
-        } else {
-            avg_position += vec_to;
-            avg_velocity += v1.0;
+        }
+        if alignment > 0.0 || cohesion > 0.0 {
+            // In color flocking mode boids care less about neighbours the further their hue is
+            // from their own, and with repulsion on actively steer away from the odd ones out
+            let mut weight = 1.0;
+            if values.modes.color_flocking {
+                let similarity = hue_similarity(color.x, other_color.x);
+                weight -= values.boid_color_affinity * (1.0 - similarity);
+                if similarity < 0.5 {
+                    vec_repel -= vec_to * (1.0 - 2.0 * similarity);
+                }
+            }
+
+            weight *= peripheral;
+            avg_position += vec_to * weight * cohesion;
+            avg_velocity += v1 * weight * alignment;
+            total_cohesion += cohesion;
+            total_alignment += alignment;
             neighboring_boids += 1;
-            total_hue += other_color.0.x;
-            total_saturation += other_color.0.y;
+            // Hues are angles, average them as unit vectors so 350 and 10 make 0 instead of 180
+            if values.modes.color_mode {
+                total_hue += Vec2::from_angle(other_color.x.to_radians());
+                total_saturation += other_color.y;
+            }
         }
     }
 
+    // Fraction of the way to the target color covered this tick
+    let blend = 1.0 - (-values.boid_color_blend_rate * dt).exp();
+    let revert = 1.0 - (-values.boid_color_revert_rate * dt).exp();
+
     if neighboring_boids > 0 {
         let neighbors = neighboring_boids as f32;
-        dv += avg_position / neighbors * values.boid_centering_factor;
-        dv += avg_velocity / neighbors * values.boid_matching_factor;
+        // Weighted averages, except that a few faint neighbours only count for as much as they
+        // weigh, so nothing jumps when one fades in at the edge of the range
+        dv += avg_position / total_cohesion.max(1.0) * values.boid_centering_factor;
+        dv += avg_velocity / total_alignment.max(1.0) * values.boid_matching_factor;
+        if values.modes.color_flocking {
+            dv += vec_repel / neighbors * values.boid_color_repulsion;
+        }
 
         // Color blending (HSL)
-        let avg_hue = total_hue / neighbors;
-        let avg_saturation = total_saturation / neighbors;
-        final_color.x = lerp(final_color.x, avg_hue, 0.1);
-        final_color.y = lerp(final_color.y, avg_saturation, 0.1);
-        // We keep the lightness (z component) constant
-    } else {
+        if values.modes.color_mode {
+            let avg_hue = total_hue.to_angle().to_degrees();
+            let avg_saturation = total_saturation / neighbors;
+            final_color.x = lerp_hue(final_color.x, avg_hue, blend);
+            final_color.y = lerp(final_color.y, avg_saturation, blend);
+            // We keep the lightness (z component) constant
+        }
+    } else if values.modes.color_mode {
         // Revert to start color when alone
-        final_color.x = lerp(final_color.x, start_color.0.x, 0.15);
-        final_color.y = lerp(final_color.y, start_color.0.y, 0.15);
+        final_color.x = lerp_hue(final_color.x, start_color.x, revert);
+        final_color.y = lerp(final_color.y, start_color.y, revert);
         // We keep the lightness (z component) constant
     }
 
     if close_boids > 0 {
-        let close = close_boids as f32;
-        dv += vec_away / close * values.boid_avoidance_factor;
+        dv += vec_away / total_separation.max(1.0) * values.boid_avoidance_factor;
     }
 
-    // Mouse chasing logic (unchanged)
-    let (camera, t_camera) = camera.single();
-    if let Some(c_window) = window.single().cursor_position() {
-        if let Some(c_world) = camera.viewport_to_world_2d(t_camera, c_window) {
-            let to_cursor = c_world - t0.translation.xy();
-            if !values.modes.mouse_predator {
-                dv += to_cursor * values.boid_mouse_chase_factor;
-            } else {
-                dv -= to_cursor * values.boid_mouse_chase_factor;
+    if evil_boids > 0 {
+        let evil = evil_boids as f32;
+        dv += vec_flee / evil * values.boid_flee_factor;
+    }
+
+    // Scatter away from any predator that gets too close
+    for image in topology.images(position, values.boid_prot_range) {
+        for (predator, _) in predators.within_distance(image, values.boid_prot_range) {
+            if let Some(away) = (-topology.offset(position, predator)).try_normalize() {
+                dv += away * values.boid_scatter_factor;
             }
-        };
-    };
+        }
+    }
+
+    dv += obstacle_avoidance(position, v0, obstacles, values, topology);
 
-    (dv, final_color)
+    // Mouse chasing logic
+    if let Some(CursorPosition(c_world)) = cursor {
+        let to_cursor = topology.offset(position, *c_world);
+        if !values.modes.mouse_predator {
+            dv += to_cursor * values.boid_mouse_chase_factor;
+        } else {
+            dv -= to_cursor * values.boid_mouse_chase_factor;
+        }
+    }
+
+    (dv, final_color, accepted)
 }
 
 // Helper function for linear interpolation
 fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + (b - a) * t
 }
+
+// Linear interpolation between two hues in degrees, the short way around the color wheel
+fn lerp_hue(a: f32, b: f32, t: f32) -> f32 {
+    let diff = (b - a + 180.).rem_euclid(360.) - 180.;
+    (a + diff * t).rem_euclid(360.)
+}
+
+/// How alike two hues (in degrees) are, going the short way around the color wheel. 1 for the same
+/// hue, 0 for opposite ones
+pub fn hue_similarity(a: f32, b: f32) -> f32 {
+    let distance = (a - b).rem_euclid(360.);
+    1.0 - distance.min(360. - distance) / 180.
+}
 /**
-* @param boid_query: Query<(Entity, &Velocity, &Transform), With<SpatialEntity>> - Query of all
-* boids
-* @param kdtree: Res<KDTree2<SpatialEntity> - The KDTree of all boids
-* @param dv_event_writer: EventWriter<DvEvent> - The event writer for the delta velocity events
-* @param camera: Query<(&Camera, &GlobalTransform)> - Query of the camera
-* @param window: Query<&Window> - Query of the window
+* @param store: ResMut<BoidStore> - Every flying boid, read from the front buffer and written to the
+* back one
+* @param neighbours: Res<Neighbours> - The neighbour index over the store, rebuilt this tick
+* @param predators: Res<KDTree2<Predator>> - The KDTree of all predators
+* @param obstacles: Query<(&Transform, &Obstacle)> - Query of all obstacles
+* @param cursor: Option<Res<CursorPosition>> - World position of the cursor, if there is one
 * @param values: Res<Values> - The values resource
-* @description The "parent" system for the boids, this is where the boids are updated as well as where
-* the threads are spawned/managed
+* @param bounds: Res<SimBounds> - Size of the simulated area, for wrapping around in toroidal mode
+* @param timing: ResMut<FlockTiming> - Where the time this run took goes
+* @param time: Res<Time> - The fixed clock, for the length of a tick
+* @description The "parent" system for the boids. Works out every boid's steering, turns it back
+* from the borders and steps it forward with the chosen integrator, each thread writing its share
+* of the boids straight into the back buffer. Evil boids don't flock, they only get the steering
+* evil_system gave them
 *
 */
+#[allow(clippy::too_many_arguments)]
 pub fn flocking_system(
-    boid_query: Query<
-        (
-            Entity,
-            &Velocity,
-            &Transform,
-            &Handle<ColorMaterial>,
-            &SimpleColor,
-        ),
-        With<SpatialEntity>,
-    >,
-    kdtree: Res<KDTree2<SpatialEntity>>,
-    mut dv_event_writer: EventWriter<DvEvent>,
-    camera: Query<(&Camera, &GlobalTransform)>,
-    window: Query<&Window>,
+    mut store: ResMut<BoidStore>,
+    neighbours: Res<Neighbours>,
+    predators: Res<KDTree2<Predator>>,
+    obstacles: Query<(&Transform, &Obstacle)>,
+    cursor: Option<Res<CursorPosition>>,
     values: Res<Values>,
+    bounds: Res<SimBounds>,
+    mut timing: ResMut<FlockTiming>,
+    time: Res<Time>,
 ) {
+    let start = Instant::now();
+    let topology = Topology::new(&bounds, &values.modes);
+    let dt = time.delta_seconds();
     let pool = ComputeTaskPool::get();
-    let boids = boid_query.iter().collect::<Vec<_>>();
-    let boids_per_thread = (boids.len() + pool.thread_num() - 1) / pool.thread_num();
+    let obstacles = obstacles.iter().collect::<Vec<_>>();
+    let boids_per_thread = store.len().div_ceil(pool.thread_num()).max(1);
 
+    // Taken out for the duration so the rest of the store can be shared between the threads
+    let mut back = std::mem::take(&mut store.back);
     // https://docs.rs/bevy/latest/bevy/tasks/struct.ComputeTaskPool.html
     // https://github.com/kvietcong/rusty-boids
-    for batch in pool.scope(|s| {
-        for chunk in boids.chunks(boids_per_thread) {
-            let kdtree = &kdtree;
-            let boid_query = &boid_query;
-            let camera = &camera;
-            let window = &window;
+    pool.scope(|s| {
+        for (chunk, out) in back.chunks_mut(boids_per_thread).enumerate() {
+            let index = neighbours.index();
+            let store = &*store;
+            let predators = &predators;
+            let obstacles = &obstacles;
+            let cursor = cursor.as_deref();
             let values = &values;
+            let bounds = &bounds;
 
             s.spawn(async move {
-                let mut dv_batch: Vec<DvEvent> = vec![];
-                let mut color_batch: Vec<ColorEvent> = vec![];
-                for (boid, _, t0, _, _) in chunk {
-                    //dv_batch.push(DvEvent(
-                    //    *boid,
-                    //    get_dv(kdtree, boid_query, camera, window, boid, t0, values),
-                    let (dv, new_color) =
-                        get_dv(kdtree, boid_query, camera, window, boid, t0, values);
-
-                    dv_batch.push(DvEvent(*boid, dv));
-                    color_batch.push(ColorEvent(*boid, new_color)); //Jeez this is uggly
+                let state = &store.front;
+                let mut found = vec![];
+                let mut nearby = vec![];
+                for i in 0..out.positions.len() {
+                    let slot = chunk * boids_per_thread + i;
+                    let (dv, color, neighbors) = if store.evil[slot] {
+                        (Vec2::ZERO, state.colors[slot], 0)
+                    } else {
+                        get_dv(
+                            index,
+                            store,
+                            predators,
+                            obstacles,
+                            cursor,
+                            slot,
+                            &mut found,
+                            &mut nearby,
+                            values,
+                            topology,
+                            dt,
+                        )
+                    };
+
+                    let position = state.positions[slot];
+                    let mut velocity = state.velocities[slot];
+                    let a = dv
+                        + store.steering[slot]
+                        + border_steering(position, &mut velocity, bounds, values);
+                    let (step, velocity) =
+                        integrate(velocity, a, state.accelerations[slot], dt, values);
+
+                    out.positions[i] = topology.wrap(position + step);
+                    out.velocities[i] = velocity;
+                    out.accelerations[i] = a;
+                    out.colors[i] = if values.modes.color_mode {
+                        color
+                    } else {
+                        state.colors[slot]
+                    };
+                    out.neighbors[i] = neighbors;
                 }
-                dv_batch
             });
         }
-    }) {
-        dv_event_writer.send_batch(batch);
-    }
+    });
+    store.back = back;
+    store.swap();
+    timing.0 = start.elapsed();
 }
 
-pub fn velo_system(
-    mut events: EventReader<DvEvent>,
-    mut boids: Query<(&mut Velocity, &mut Transform)>,
-    window: Query<&Window>,
-    values: Res<Values>,
-) {
-    for DvEvent(boid, dv) in events.read() {
-        let Ok((mut velocity, transform)) = boids.get_mut(*boid) else {
-            continue;
-        };
-
-        velocity.0 += *dv;
-
-        let window = window.single();
-        let width = ((window.width() - values.boid_bound_size) / 2.) as f32;
-        let height = ((window.height() - values.boid_bound_size) / 2.) as f32;
+// Gentle turning as a boid gets near the border, and a hard bounce if it gets well past it. Off
+// on a torus, which has no border
+fn border_steering(
+    position: Vec2,
+    velocity: &mut Vec2,
+    bounds: &SimBounds,
+    values: &Values,
+) -> Vec2 {
+    let mut a = Vec2::ZERO;
+    if values.modes.toroidal {
+        return a;
+    }
+    let width = (bounds.0.x - values.boid_bound_size) / 2.;
+    let height = (bounds.0.y - values.boid_bound_size) / 2.;
 
-        let pos_x = transform.translation.x;
-        let pos_y = transform.translation.y;
+    if position.x < -width {
+        a.x += values.boid_turn_factor;
+    }
+    if position.x > width {
+        a.x -= values.boid_turn_factor;
+    }
+    if position.y < -height {
+        a.y += values.boid_turn_factor;
+    }
+    if position.y > height {
+        a.y -= values.boid_turn_factor;
+    }
 
-        if !values.modes.toroidal {
-            // Gentle turning when approaching borders
-            if pos_x < -width {
-                velocity.0.x += values.boid_turn_factor;
-            }
-            if pos_x > width {
-                velocity.0.x -= values.boid_turn_factor;
-            }
-            if pos_y < -height {
-                velocity.0.y += values.boid_turn_factor;
-            }
-            if pos_y > height {
-                velocity.0.y -= values.boid_turn_factor;
-            }
+    // Only apply hard limits if really necessary
+    if position.x < -width - 50.0 {
+        velocity.x = velocity.x.abs();
+    }
+    if position.x > width + 50.0 {
+        velocity.x = -velocity.x.abs();
+    }
+    if position.y < -height - 50.0 {
+        velocity.y = velocity.y.abs();
+    }
+    if position.y > height + 50.0 {
+        velocity.y = -velocity.y.abs();
+    }
+    a
+}
 
-            // Only apply hard limits if really necessary
-            if pos_x < -width - 50.0 {
-                velocity.0.x = velocity.0.x.abs();
-            }
-            if pos_x > width + 50.0 {
-                velocity.0.x = -velocity.0.x.abs();
-            }
-            if pos_y < -height - 50.0 {
-                velocity.0.y = velocity.0.y.abs();
-            }
-            if pos_y > height + 50.0 {
-                velocity.0.y = -velocity.0.y.abs();
-            }
+// One tick of `dt` seconds with acceleration `a`, given the acceleration of the tick before.
+// Returns how far the boid moves and its new velocity, kept between the minimum and maximum speed
+fn integrate(v0: Vec2, a: Vec2, previous: Vec2, dt: f32, values: &Values) -> (Vec2, Vec2) {
+    match values.integrator {
+        Integrator::Euler => (v0 * dt, clamp_speed(v0 + a * dt, values)),
+        Integrator::SemiImplicitEuler => {
+            let v = clamp_speed(v0 + a * dt, values);
+            (v * dt, v)
         }
+        Integrator::Verlet => (
+            v0 * dt + a / 2. * dt * dt,
+            clamp_speed(v0 + (previous + a) / 2. * dt, values),
+        ),
+    }
+}
 
-        // Clamp speed
-        let speed = velocity.0.length();
-        if speed < values.boid_min_speed {
-            velocity.0 *= values.boid_min_speed / speed;
-        } else if speed > values.boid_max_speed {
-            velocity.0 *= values.boid_max_speed / speed;
-        }
+// A boid that's stopped dead has no direction to speed up in, so it stays stopped until something
+// steers it
+fn clamp_speed(velocity: Vec2, values: &Values) -> Vec2 {
+    let speed = velocity.length();
+    if speed < values.boid_min_speed {
+        velocity.normalize_or_zero() * values.boid_min_speed
+    } else if speed > values.boid_max_speed {
+        velocity * (values.boid_max_speed / speed)
+    } else {
+        velocity
     }
 }
-pub fn movement_system(mut query: Query<(&mut Velocity, &mut Transform)>) {
+
+// Moves everything that has a velocity but isn't a boid, which flocking_system moves itself. That's
+// the predators, they steer themselves and fly at a constant speed
+pub fn movement_system(
+    mut query: Query<(&Velocity, &mut Transform), Without<SpatialEntity>>,
+    bounds: Res<SimBounds>,
+    values: Res<Values>,
+    time: Res<Time>,
+) {
+    let topology = Topology::new(&bounds, &values.modes);
+    let dt = time.delta_seconds();
     for (velocity, mut transform) in query.iter_mut() {
         transform.rotation = Quat::from_axis_angle(Vec3::Z, steer_to(Vec2::ZERO, velocity.0));
-        transform.translation.x += velocity.0.x;
-        transform.translation.y += velocity.0.y;
+        let position = topology.wrap(transform.translation.xy() + velocity.0 * dt);
+        transform.translation = position.extend(transform.translation.z);
     }
 }
+
 fn steer_to(a: Vec2, b: Vec2) -> f32 {
     // https://stackoverflow.com/a/68929139
     let dir = b - a;
     dir.y.atan2(dir.x)
 }
 
-// function that when called, will update the color of the current boid to be a bit closer to the
-// average color of the boids in it's neighborhood, if the neighborhood is less than a certain
-// size, it will slowly start to revert that change back to it's start_color
-
-pub fn setup_bounds(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+// Stragglers that have been on their own for too long pick a fresh random color and stick with it,
+// so the flock doesn't end up all the same color
+pub fn loneliness_system(
+    mut boids: Query<
+        (
+            &Neighbors,
+            &mut Loneliness,
+            &mut SimpleColor,
+            &mut StartColor,
+        ),
+        Flocking,
+    >,
+    mut rng: ResMut<SimRng>,
+    time: Res<Time>,
+    values: Res<Values>,
 ) {
-    // Create a rectangular border
-    let border_thickness = 2.0;
-
-    // Top border
-    commands.spawn(SpriteBundle {
-        sprite: Sprite {
-            color: Color::WHITE,
-            custom_size: Some(Vec2::new(BOUNDS.x, border_thickness)),
-            ..default()
-        },
-        transform: Transform::from_translation(Vec3::new(0.0, BOUNDS.y / 2.0, 0.0)),
-        ..default()
-    });
+    let lonely_time = std::time::Duration::from_secs_f32(values.boid_lonely_time.max(0.));
 
-    // Bottom border
-    commands.spawn(SpriteBundle {
-        sprite: Sprite {
-            color: Color::WHITE,
-            custom_size: Some(Vec2::new(BOUNDS.x, border_thickness)),
-            ..default()
-        },
-        transform: Transform::from_translation(Vec3::new(0.0, -BOUNDS.y / 2.0, 0.0)),
-        ..default()
-    });
+    for (neighbors, mut loneliness, mut color, mut start_color) in boids.iter_mut() {
+        if loneliness.0.duration() != lonely_time {
+            loneliness.0.set_duration(lonely_time);
+        }
+
+        if neighbors.0 > 0 {
+            loneliness.0.reset();
+            continue;
+        }
 
-    // Left border
-    commands.spawn(SpriteBundle {
-        sprite: Sprite {
-            color: Color::WHITE,
-            custom_size: Some(Vec2::new(border_thickness, BOUNDS.y)),
+        if loneliness.0.tick(time.delta()).just_finished() {
+            *color = SimpleColor::random(&mut rng.0);
+            start_color.0 = color.0;
+            loneliness.0.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neighbours::neighbour_index_system;
+    use crate::store::load_store_system;
+    use bevy::ecs::system::RunSystemOnce;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // How many of the boids at `flockmates` a boid at the origin flying along +x counts as
+    // neighbours, with evil boids at `hunters` around as well
+    fn seen(mut values: Values, flockmates: &[Vec2], hunters: &[Vec2]) -> usize {
+        values.update_derived();
+        let mut world = World::new();
+        world.insert_resource(values);
+        world.init_resource::<BoidStore>();
+        world.init_resource::<Neighbours>();
+        world.init_resource::<KDTree2<Predator>>();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let boid = world
+            .spawn(BoidBundle::random(&mut rng, Vec2::ZERO, &values))
+            .insert(Velocity(Vec2::X * values.boid_max_speed))
+            .id();
+        for &position in flockmates {
+            world.spawn(BoidBundle::random(&mut rng, position, &values));
+        }
+        for &position in hunters {
+            world
+                .spawn(BoidBundle::random(&mut rng, position, &values))
+                .insert(Evil {
+                    cooldown: Timer::default(),
+                });
+        }
+
+        world.run_system_once(load_store_system);
+        world.run_system_once(neighbour_index_system);
+        world.run_system_once(
+            move |store: Res<BoidStore>,
+                  neighbours: Res<Neighbours>,
+                  predators: Res<KDTree2<Predator>>,
+                  values: Res<Values>| {
+                let slot = store.slot(boid).unwrap();
+                let topology = Topology::new(&SimBounds::default(), &values.modes);
+                let (mut found, mut nearby) = (vec![], vec![]);
+                let (.., count) = get_dv(
+                    neighbours.index(),
+                    &store,
+                    &predators,
+                    &[],
+                    None,
+                    slot,
+                    &mut found,
+                    &mut nearby,
+                    &values,
+                    topology,
+                    1. / 60.,
+                );
+                count
+            },
+        )
+    }
+
+    // A boid 20 units away, `degrees` off the way the boid at the origin is flying
+    fn at(degrees: f32) -> Vec2 {
+        Vec2::from_angle(degrees.to_radians()) * 20.
+    }
+
+    #[test]
+    fn field_of_view_and_blind_spot() {
+        // 240° of view is 120° to either side
+        let values = Values {
+            boid_fov: 240f32.to_radians(),
+            boid_blind_spot: 0.,
             ..default()
-        },
-        transform: Transform::from_translation(Vec3::new(-BOUNDS.x / 2.0, 0.0, 0.0)),
-        ..default()
-    });
+        };
+        assert_eq!(seen(values, &[at(119.)], &[]), 1);
+        assert_eq!(seen(values, &[at(-119.)], &[]), 1);
+        assert_eq!(seen(values, &[at(121.)], &[]), 0);
+        assert_eq!(seen(values, &[at(-121.)], &[]), 0);
+
+        // A 60° blind spot hides everything more than 150° off the heading, whatever the view
+        let values = Values {
+            boid_fov: 360f32.to_radians(),
+            boid_blind_spot: 60f32.to_radians(),
+            ..default()
+        };
+        assert_eq!(seen(values, &[at(149.)], &[]), 1);
+        assert_eq!(seen(values, &[at(151.)], &[]), 0);
+        assert_eq!(seen(values, &[at(180.)], &[]), 0);
+    }
+
+    #[test]
+    fn hunters_are_not_company() {
+        let values = Values::default();
+        assert_eq!(seen(values, &[], &[at(0.)]), 0);
+        assert_eq!(seen(values, &[at(10.)], &[at(-10.)]), 1);
+    }
 
-    // Right border
-    commands.spawn(SpriteBundle {
-        sprite: Sprite {
-            color: Color::WHITE,
-            custom_size: Some(Vec2::new(border_thickness, BOUNDS.y)),
+    #[test]
+    fn kernels_fall_off_to_the_edge_of_their_range() {
+        let mut values = Values::default();
+        values.update_derived();
+        let (prot, vis) = (values.boid_prot_range, values.boid_vis_range);
+        let x_sq = (prot / vis).powi(2);
+        let falloff = |f: fn(f32) -> f32| (f(x_sq) - f(1.0)) / (f(0.0) - f(1.0));
+        for (kernel, at_prot_range) in [
+            (Kernel::Linear, 1.0 - prot / vis),
+            (
+                Kernel::InverseSquare,
+                falloff(|x_sq| 1.0 / (1.0 + 16.0 * x_sq)),
+            ),
+            (Kernel::Gaussian, falloff(|x_sq| (-4.5 * x_sq).exp())),
+        ] {
+            // Alignment and cohesion reach out to the visual range
+            let weight = |dist: f32| kernel.weight(dist * dist, 0, values.vis_range_sq, &values);
+            assert_eq!(weight(0.), 1.0, "{kernel:?}");
+            assert!((weight(prot) - at_prot_range).abs() < 1e-5, "{kernel:?}");
+            assert!(weight(prot) > 0. && weight(prot) < 1., "{kernel:?}");
+            assert_eq!(weight(vis), 0.0, "{kernel:?}");
+
+            // Separation only to the protected range
+            let weight = |dist: f32| kernel.weight(dist * dist, 0, values.prot_range_sq, &values);
+            assert_eq!(weight(0.), 1.0, "{kernel:?}");
+            assert_eq!(weight(prot), 0.0, "{kernel:?}");
+            assert_eq!(weight(vis), 0.0, "{kernel:?}");
+        }
+    }
+
+    #[test]
+    fn topological_keeps_the_nearest_few() {
+        let values = Values {
+            boid_topological_neighbors: 3,
+            separation_kernel: Kernel::Topological,
+            alignment_kernel: Kernel::Topological,
+            cohesion_kernel: Kernel::Topological,
             ..default()
-        },
-        transform: Transform::from_translation(Vec3::new(BOUNDS.x / 2.0, 0.0, 0.0)),
-        ..default()
-    });
-}
+        };
+        let weight = |rank| Kernel::Topological.weight(1., rank, 100., &values);
+        assert_eq!([0, 1, 2, 3, 4].map(weight), [1., 1., 1., 0., 0.]);
 
-pub fn color_change_system(
-    mut color_events: EventReader<ColorEvent>,
-    mut boids: Query<(&mut SimpleColor, &Handle<ColorMaterial>)>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-) {
-    for ColorEvent(boid, new_color) in color_events.read() {
-        if let Ok((mut simple_color, material_handle)) = boids.get_mut(*boid) {
-            simple_color.0 = *new_color;
-            if let Some(material) = materials.get_mut(material_handle) {
-                material.color = Color::hsl(new_color.x, new_color.y, new_color.z);
-            }
+        // Six in view and outside the protected range, only the three nearest count
+        let flockmates: Vec<Vec2> = (0..6).map(|i| Vec2::new(12. + 3. * i as f32, 0.)).collect();
+        assert_eq!(seen(values, &flockmates, &[]), 3);
+    }
+
+    // Steps a boid `ticks` times under a constant acceleration, returning where it ends up and how
+    // fast it's going
+    fn fly(integrator: Integrator, v0: Vec2, a: Vec2, dt: f32, ticks: usize) -> (Vec2, Vec2) {
+        // No speed limits to get in the way
+        let values = Values {
+            integrator,
+            boid_min_speed: 0.,
+            boid_max_speed: f32::INFINITY,
+            ..default()
+        };
+        let (mut position, mut velocity) = (Vec2::ZERO, v0);
+        for _ in 0..ticks {
+            let (dx, v) = integrate(velocity, a, a, dt, &values);
+            position += dx;
+            velocity = v;
         }
+        (position, velocity)
+    }
+
+    fn assert_close(actual: Vec2, expected: Vec2) {
+        assert!(
+            actual.abs_diff_eq(expected, 1e-3 * expected.length().max(1.)),
+            "{actual} isn't {expected}"
+        );
+    }
+
+    #[test]
+    fn integrators_under_constant_acceleration() {
+        let (v0, a, dt, n) = (Vec2::new(10., 5.), Vec2::new(3., -2.), 1. / 60., 120);
+        let t = dt * n as f32;
+        let velocity = v0 + a * t;
+
+        // Euler moves with the old velocity, so every tick but the first misses a tick's worth of
+        // speeding up, semi-implicit Euler moves with the new one and gets an extra tick's worth
+        let steps = (n * (n - 1) / 2) as f32;
+        let (position, v) = fly(Integrator::Euler, v0, a, dt, n);
+        assert_close(position, v0 * t + a * dt * dt * steps);
+        assert_close(v, velocity);
+
+        let steps = (n * (n + 1) / 2) as f32;
+        let (position, v) = fly(Integrator::SemiImplicitEuler, v0, a, dt, n);
+        assert_close(position, v0 * t + a * dt * dt * steps);
+        assert_close(v, velocity);
+
+        // Verlet is exact for a constant acceleration
+        let (position, v) = fly(Integrator::Verlet, v0, a, dt, n);
+        assert_close(position, v0 * t + a / 2. * t * t);
+        assert_close(v, velocity);
     }
 }
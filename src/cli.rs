@@ -0,0 +1,347 @@
+use crate::boid::{Integrator, SimpleColor, SpatialEntity, Velocity};
+use crate::flock::FlockEventLog;
+use crate::metrics::MetricsExporter;
+use crate::neighbours::NeighbourBackend;
+use crate::predator::PredatorStrategy;
+use crate::presets;
+use crate::record::{Recorder, Recording};
+use crate::replay::Replay;
+use crate::sim::{SimSet, SimTick, TICK_HZ};
+use crate::{SimBounds, SimSeed, Values};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Boids, flocking on your screen or as fast as your CPU allows without one
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    /// Preset to start from, either a file or the name of one in assets/presets
+    #[arg(short, long, value_name = "NAME|PATH")]
+    pub preset: Option<String>,
+
+    /// Number of boids, overrides the preset
+    #[arg(short = 'n', long, value_name = "COUNT")]
+    pub boids: Option<u32>,
+
+    /// Seed for the simulation, the same seed and settings always give the same run
+    #[arg(short, long)]
+    pub seed: Option<u64>,
+
+    /// Width of the window, or of the world when running headless
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub width: Option<u32>,
+
+    /// Height of the window, or of the world when running headless
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub height: Option<u32>,
+
+    /// Run without a window, ticking as fast as possible
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Simulation ticks per second. Higher rates step the motion more finely, the boids fly just
+    /// as fast either way
+    #[arg(long, value_name = "HZ", default_value_t = TICK_HZ)]
+    pub tick_rate: f64,
+
+    /// How velocities and positions are stepped forward each tick, overrides the preset
+    #[arg(long, value_enum)]
+    pub integrator: Option<IntegratorArg>,
+
+    /// How boids find their neighbours, overrides the preset
+    #[arg(long, value_enum)]
+    pub neighbour_index: Option<NeighbourArg>,
+
+    /// Quit after this many simulation ticks
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub ticks: Option<u64>,
+
+    /// Write every boid's position, velocity and HSL color to this CSV file when the run ends
+    #[arg(short, long, value_name = "PATH", requires = "ticks")]
+    pub output: Option<PathBuf>,
+
+    /// Record every tick of the run to this file, to watch again with --replay
+    #[arg(short, long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Write flock metrics (speed, polarization, milling, nearest neighbour distances, clusters)
+    /// to this CSV file as the run goes
+    #[arg(short, long, value_name = "PATH")]
+    pub metrics: Option<PathBuf>,
+
+    /// Ticks between rows of metrics
+    #[arg(long, value_name = "TICKS", default_value_t = 1, requires = "metrics",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    pub metrics_every: u64,
+
+    /// Write every flock birth, death, split and merge to this file, one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    pub flock_events: Option<PathBuf>,
+
+    /// Play a recording back instead of simulating, with controls to jump around in it
+    #[arg(long, value_name = "PATH", conflicts_with_all = [
+        "preset", "boids", "seed", "headless", "ticks", "output", "save_preset", "record",
+        "toroidal", "mouse_predator", "predators", "predator_strategy", "metrics", "flock_events",
+        "tick_rate", "integrator", "neighbour_index",
+    ])]
+    pub replay: Option<PathBuf>,
+
+    /// Write the settings this run starts with to a preset file, .ron or .toml
+    #[arg(long, value_name = "PATH")]
+    pub save_preset: Option<PathBuf>,
+
+    /// Wrap around the edges of the world instead of turning back
+    #[arg(long)]
+    pub toroidal: bool,
+
+    /// Make the boids flee the mouse instead of chasing it
+    #[arg(long)]
+    pub mouse_predator: bool,
+
+    /// Number of predators hunting the flock, overrides the preset
+    #[arg(long, value_name = "COUNT")]
+    pub predators: Option<u32>,
+
+    /// How predators pick what to chase, overrides the preset
+    #[arg(long, value_enum)]
+    pub predator_strategy: Option<StrategyArg>,
+}
+
+// PredatorStrategy lives in the simulation, which doesn't know about clap
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum StrategyArg {
+    Nearest,
+    MostIsolated,
+    LargestCluster,
+}
+
+impl From<StrategyArg> for PredatorStrategy {
+    fn from(arg: StrategyArg) -> Self {
+        match arg {
+            StrategyArg::Nearest => PredatorStrategy::Nearest,
+            StrategyArg::MostIsolated => PredatorStrategy::MostIsolated,
+            StrategyArg::LargestCluster => PredatorStrategy::LargestCluster,
+        }
+    }
+}
+
+// Same for Integrator
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum IntegratorArg {
+    Euler,
+    SemiImplicitEuler,
+    Verlet,
+}
+
+impl From<IntegratorArg> for Integrator {
+    fn from(arg: IntegratorArg) -> Self {
+        match arg {
+            IntegratorArg::Euler => Integrator::Euler,
+            IntegratorArg::SemiImplicitEuler => Integrator::SemiImplicitEuler,
+            IntegratorArg::Verlet => Integrator::Verlet,
+        }
+    }
+}
+
+// Same for NeighbourBackend
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum NeighbourArg {
+    KdTree,
+    Grid,
+}
+
+impl From<NeighbourArg> for NeighbourBackend {
+    fn from(arg: NeighbourArg) -> Self {
+        match arg {
+            NeighbourArg::KdTree => NeighbourBackend::KdTree,
+            NeighbourArg::Grid => NeighbourBackend::Grid,
+        }
+    }
+}
+
+impl Cli {
+    /// Parses the command line, printing help or the problem and exiting if that's all it can do
+    pub fn parse_and_validate() -> Self {
+        let cli = Cli::parse();
+        if let Err(err) = cli.values() {
+            Cli::command().error(ErrorKind::ValueValidation, err).exit();
+        }
+        if !(cli.tick_rate.is_finite() && cli.tick_rate > 0.) {
+            Cli::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    "the tick rate has to be a positive number",
+                )
+                .exit();
+        }
+        cli
+    }
+
+    /// Opens the recording to play back, if there is one, exiting if it can't be read
+    pub fn replay(&self) -> Option<Replay> {
+        let path = self.replay.as_ref()?;
+        match Recording::open(path) {
+            Ok(recording) => Some(Replay::new(recording)),
+            Err(err) => Cli::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("couldn't read recording {}: {err}", path.display()),
+                )
+                .exit(),
+        }
+    }
+
+    /// The preset, or the defaults, with every override on the command line applied
+    pub fn values(&self) -> Result<Values, String> {
+        let mut values = match &self.preset {
+            Some(name) => presets::find_preset(name)
+                .map_err(|err| format!("couldn't load preset {name}: {err}"))?,
+            None => Values::default(),
+        };
+        if let Some(boids) = self.boids {
+            values.boid_count = i32::try_from(boids).map_err(|_| "too many boids")?;
+        }
+        if let Some(predators) = self.predators {
+            values.predator_count = i32::try_from(predators).map_err(|_| "too many predators")?;
+        }
+        if let Some(strategy) = self.predator_strategy {
+            values.predator_strategy = strategy.into();
+        }
+        if let Some(integrator) = self.integrator {
+            values.integrator = integrator.into();
+        }
+        if let Some(backend) = self.neighbour_index {
+            values.neighbour_backend = backend.into();
+        }
+        values.modes.toroidal |= self.toroidal;
+        values.modes.mouse_predator |= self.mouse_predator;
+        values.update_derived();
+        Ok(values)
+    }
+
+    /// Window or world size from the command line, with whatever's missing taken from `default`
+    pub fn size(&self, default: Vec2) -> Vec2 {
+        Vec2::new(
+            self.width.map_or(default.x, |width| width as f32),
+            self.height.map_or(default.y, |height| height as f32),
+        )
+    }
+
+    /// Puts everything the command line asked for into the app, before it starts so the first
+    /// boids are already spawned with it. Logging has to be set up already
+    pub fn apply(&self, app: &mut App) {
+        // Already validated in parse_and_validate
+        let values = self.values().unwrap_or_default();
+        app.insert_resource(values)
+            .insert_resource(Time::<Fixed>::from_hz(self.tick_rate));
+        if let Some(seed) = self.seed {
+            app.insert_resource(SimSeed(seed));
+        }
+        if self.headless {
+            // With a window the bounds follow it instead
+            app.insert_resource(SimBounds(self.size(SimBounds::default().0)));
+            if self.ticks.is_none() {
+                warn!("running headless without --ticks, this won't stop on its own");
+            }
+        }
+        if let Some(path) = &self.save_preset {
+            if let Err(err) = presets::save_preset(path, &values) {
+                error!("couldn't save preset to {}: {err}", path.display());
+            }
+        }
+        if let Some(path) = &self.record {
+            match Recorder::create(path, &values, self.tick_rate) {
+                Ok(recorder) => app.insert_resource(recorder),
+                Err(err) => Cli::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("couldn't record to {}: {err}", path.display()),
+                    )
+                    .exit(),
+            };
+        }
+        if let Some(path) = &self.metrics {
+            match MetricsExporter::create(path, self.metrics_every) {
+                Ok(exporter) => app.insert_resource(exporter),
+                Err(err) => Cli::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("couldn't write metrics to {}: {err}", path.display()),
+                    )
+                    .exit(),
+            };
+        }
+        if let Some(path) = &self.flock_events {
+            match FlockEventLog::create(path) {
+                Ok(log) => app.insert_resource(log),
+                Err(err) => Cli::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("couldn't write flock events to {}: {err}", path.display()),
+                    )
+                    .exit(),
+            };
+        }
+        if let Some(ticks) = self.ticks {
+            app.insert_resource(RunLength {
+                ticks,
+                output: self.output.clone(),
+            })
+            .add_systems(FixedUpdate, exit_after_ticks_system.after(SimSet));
+        }
+    }
+}
+
+/// How long a run from the command line goes on for and what it leaves behind
+#[derive(Resource, Clone, Debug)]
+pub struct RunLength {
+    pub ticks: u64,
+    pub output: Option<PathBuf>,
+}
+
+pub fn exit_after_ticks_system(
+    tick: Res<SimTick>,
+    run: Res<RunLength>,
+    boids: Query<(Entity, &Transform, &Velocity, &SimpleColor), With<SpatialEntity>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // Exactly on the last tick, more ticks can still run in the same frame after asking to exit
+    if !tick.is_changed() || tick.0 != run.ticks {
+        return;
+    }
+
+    if let Some(path) = &run.output {
+        if let Err(err) = write_snapshot(path, &boids) {
+            error!("couldn't write {}: {err}", path.display());
+            exit.send(AppExit::from_code(1));
+            return;
+        }
+        info!("wrote {} boids to {}", boids.iter().len(), path.display());
+    }
+    exit.send(AppExit::Success);
+}
+
+fn write_snapshot(
+    path: &Path,
+    boids: &Query<(Entity, &Transform, &Velocity, &SimpleColor), With<SpatialEntity>>,
+) -> std::io::Result<()> {
+    // Sorted so the same run always writes the same file
+    let mut rows: Vec<_> = boids.iter().collect();
+    rows.sort_unstable_by_key(|(entity, ..)| *entity);
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "x,y,vx,vy,hue,saturation,lightness")?;
+    for (_, transform, velocity, color) in rows {
+        let position = transform.translation;
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{}",
+            position.x, position.y, velocity.0.x, velocity.0.y, color.0.x, color.0.y, color.0.z
+        )?;
+    }
+    file.flush()
+}
@@ -0,0 +1,133 @@
+use crate::boid::{Flocking, Neighbors, SimpleColor, StartColor};
+use crate::neighbours::Neighbours;
+use crate::store::BoidStore;
+use crate::SimBounds;
+use crate::Topology;
+use crate::Values;
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// Color evil boids take on so you can tell them apart from the flock
+pub const EVIL_COLOR: Vec3 = Vec3::new(0.0, 1.0, 0.5);
+
+/// A boid that's turned on the flock. It chases the nearest boid that isn't evil and kills it on
+/// contact, then has to wait for the cooldown before it can kill again
+#[derive(Component)]
+pub struct Evil {
+    pub cooldown: Timer,
+}
+
+/// Runs while a boid can't see anyone else, and resets as soon as it can. In evil mode a boid that
+/// stays alone for the whole `boid_evil_time` turns evil
+#[derive(Component)]
+pub struct Neglect(pub Timer);
+
+/// How many boids have been killed by evil boids so far
+#[derive(Resource, Default)]
+pub struct KillCount(pub u32);
+
+pub fn neglect_system(
+    mut commands: Commands,
+    mut boids: Query<(Entity, &Neighbors, &mut Neglect, &mut SimpleColor), Flocking>,
+    mut evil: Query<(Entity, &mut SimpleColor, &StartColor), With<Evil>>,
+    time: Res<Time>,
+    values: Res<Values>,
+) {
+    // Everyone makes up once evil mode is turned off
+    if !values.modes.evil {
+        for (boid, mut color, start_color) in evil.iter_mut() {
+            color.0 = start_color.0;
+            commands.entity(boid).remove::<Evil>();
+        }
+        return;
+    }
+
+    let evil_time = Duration::from_secs_f32(values.boid_evil_time.max(0.));
+
+    for (boid, neighbors, mut neglect, mut color) in boids.iter_mut() {
+        if neglect.0.duration() != evil_time {
+            neglect.0.set_duration(evil_time);
+        }
+
+        if neighbors.0 > 0 {
+            neglect.0.reset();
+            continue;
+        }
+
+        if neglect.0.tick(time.delta()).just_finished() {
+            neglect.0.reset();
+            color.0 = EVIL_COLOR;
+            commands.entity(boid).insert(Evil {
+                cooldown: Timer::from_seconds(values.boid_kill_cooldown.max(0.), TimerMode::Once),
+            });
+        }
+    }
+}
+
+/**
+* @param store: ResMut<BoidStore> - Every flying boid, hunters get their steering written into it
+* @param neighbours: Res<Neighbours> - The neighbour index over the store
+* @param hunters: Query<&mut Evil> - Query of all evil boids
+* @param kills: ResMut<KillCount> - Running total of kills
+* @param bounds: Res<SimBounds> - Size of the simulated area, for wrapping around in toroidal mode
+* @param values: ResMut<Values> - The values resource
+* @description Evil boids skip the flocking rules, instead they steer straight for the nearest boid
+* that isn't evil (flocking_system picks the steering up from the store) and despawn it once it's
+* within `boid_kill_radius`. Perched boids are safe. Kills come off `boid_count` so
+* population_system doesn't bring the victims straight back
+*
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn evil_system(
+    mut commands: Commands,
+    mut store: ResMut<BoidStore>,
+    neighbours: Res<Neighbours>,
+    mut hunters: Query<&mut Evil>,
+    mut kills: ResMut<KillCount>,
+    time: Res<Time>,
+    bounds: Res<SimBounds>,
+    mut values: ResMut<Values>,
+) {
+    for mut evil in hunters.iter_mut() {
+        evil.cooldown.tick(time.delta());
+    }
+
+    let store = &mut *store;
+    let topology = Topology::new(&bounds, &values.modes);
+    let mut killed = vec![false; store.len()];
+    let kill_radius_sq = values.boid_kill_radius * values.boid_kill_radius;
+
+    for slot in 0..store.len() {
+        if !store.evil[slot] {
+            continue;
+        }
+        let Ok(mut evil) = hunters.get_mut(store.entities()[slot]) else {
+            continue;
+        };
+
+        let position = store.front.positions[slot];
+        let Some(target) = neighbours.nearest(
+            &store.front.positions,
+            position,
+            values.boid_vis_range,
+            topology,
+            |other| !store.evil[other] && !killed[other],
+        ) else {
+            continue;
+        };
+
+        let to_target = topology.offset(position, store.front.positions[target]);
+        if to_target.length_squared() <= kill_radius_sq && evil.cooldown.finished() {
+            commands.entity(store.entities()[target]).despawn();
+            killed[target] = true;
+            kills.0 += 1;
+            values.boid_count -= 1;
+            evil.cooldown.reset();
+            continue;
+        }
+
+        let desired = to_target.normalize_or_zero() * values.boid_max_speed;
+        store.steering[slot] =
+            (desired - store.front.velocities[slot]) * values.boid_pursuit_factor;
+    }
+}
@@ -0,0 +1,456 @@
+use crate::boid::SpatialEntity;
+use crate::neighbours::Neighbours;
+use crate::sim::SimTick;
+use crate::store::BoidStore;
+use crate::{SimBounds, Topology, Values};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Which flock a boid belongs to. Ids carry over from tick to tick for as long as most of the
+/// flock stays together, boids in groups smaller than `flock_min_size` don't have one
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct FlockId(pub u32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlockInfo {
+    pub size: usize,
+    pub centroid: Vec2,
+    /// Direction the flock as a whole is flying in, zero if it's going nowhere
+    pub heading: Vec2,
+}
+
+/// A flock at the moment something happened to it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct FlockSummary {
+    pub id: FlockId,
+    pub size: usize,
+    pub centroid: Vec2,
+}
+
+/// Something that happened to a flock on `tick`. A flock that just carries on as one, gaining or
+/// losing a few boids on the way, doesn't make any
+#[derive(Event, Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FlockEvent {
+    /// A flock formed out of boids that weren't in one
+    Birth { tick: u64, flock: FlockSummary },
+    /// A flock broke up into groups too small to count, or was eaten
+    Death { tick: u64, flock: FlockSummary },
+    /// A flock broke into several, one of which usually keeps its id
+    Split {
+        tick: u64,
+        from: FlockSummary,
+        into: Vec<FlockSummary>,
+    },
+    /// Several flocks came together into one, which keeps the id of whichever brought the most
+    Merge {
+        tick: u64,
+        from: Vec<FlockSummary>,
+        into: FlockSummary,
+    },
+}
+
+/// Insert to have the boids grouped into flocks every tick even without a [`FlockEventLog`],
+/// for anything else that reads [`FlockEvent`]s or [`FlockId`]s. Flocks aren't tracked at all
+/// otherwise, finding them costs about as much as the flocking itself
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct TrackFlocks;
+
+/// Whether anything wants flocks tracked this tick, see [`TrackFlocks`]
+pub fn tracking_flocks(log: Option<Res<FlockEventLog>>, track: Option<Res<TrackFlocks>>) -> bool {
+    log.is_some() || track.is_some()
+}
+
+/// Every flock as of the last tick, by id
+#[derive(Resource, Default)]
+pub struct Flocks {
+    pub flocks: BTreeMap<FlockId, FlockInfo>,
+    next_id: u32,
+}
+
+impl Flocks {
+    fn new_id(&mut self) -> FlockId {
+        self.next_id += 1;
+        FlockId(self.next_id - 1)
+    }
+}
+
+fn summary(flocks: &BTreeMap<FlockId, FlockInfo>, id: FlockId) -> FlockSummary {
+    let info = flocks[&id];
+    FlockSummary {
+        id,
+        size: info.size,
+        centroid: info.centroid,
+    }
+}
+
+/// Disjoint sets over `0..n`, for finding connected components
+pub struct UnionFind(Vec<usize>);
+
+impl UnionFind {
+    pub fn new(n: usize) -> Self {
+        Self((0..n).collect())
+    }
+
+    /// Representative of the set `i` is in, the smallest index in it
+    pub fn root(&mut self, mut i: usize) -> usize {
+        while self.0[i] != i {
+            self.0[i] = self.0[self.0[i]];
+            i = self.0[i];
+        }
+        i
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        self.0[a.max(b)] = a.min(b);
+    }
+}
+
+/**
+* @param flocks: &mut Flocks - The flocks as of last tick, replaced with the new ones
+* @param previous: &[Vec<Option<FlockId>>] - For every new flock, the old flock each of its boids was in
+* @param infos: &[FlockInfo] - Size, centroid and heading of every new flock
+* @param tick: u64 - The tick the new flocks are for
+* @return (Vec<FlockId>, Vec<FlockEvent>) - The id of every new flock, and what happened to the flocks
+* @description Matches the new flocks up with the old ones. An old and a new flock are only linked
+* if at least half of the smaller of the two went from one to the other, so a few boids wandering
+* between flocks don't count for anything. Each new flock takes over the id of the old flock it's
+* linked to by the most boids, biggest links first, anything left over gets a brand new id. The
+* links give the events: an old flock linked to nothing died, one linked to several split, a new
+* flock linked to nothing was born and one linked to several is a merge
+*
+*/
+pub fn track_flocks(
+    flocks: &mut Flocks,
+    previous: &[Vec<Option<FlockId>>],
+    infos: &[FlockInfo],
+    tick: u64,
+) -> (Vec<FlockId>, Vec<FlockEvent>) {
+    let old_flocks = std::mem::take(&mut flocks.flocks);
+
+    // How many boids each new flock got from each old one, largest links get first pick
+    let mut links: Vec<(usize, FlockId, usize)> = vec![];
+    for (c, (component, info)) in previous.iter().zip(infos).enumerate() {
+        let mut counts: BTreeMap<FlockId, usize> = BTreeMap::new();
+        for &id in component.iter().flatten() {
+            *counts.entry(id).or_default() += 1;
+        }
+        links.extend(counts.into_iter().filter_map(|(id, count)| {
+            let old_size = old_flocks.get(&id).map_or(0, |old| old.size);
+            (count * 2 >= old_size.min(info.size)).then_some((c, id, count))
+        }));
+    }
+    links.sort_unstable_by_key(|&(c, id, count)| (std::cmp::Reverse(count), id, c));
+
+    let mut ids: Vec<Option<FlockId>> = vec![None; infos.len()];
+    let mut taken: Vec<FlockId> = vec![];
+    for &(c, id, _) in &links {
+        if ids[c].is_none() && !taken.contains(&id) {
+            ids[c] = Some(id);
+            taken.push(id);
+        }
+    }
+    let ids: Vec<FlockId> = ids
+        .into_iter()
+        .map(|id| id.unwrap_or_else(|| flocks.new_id()))
+        .collect();
+    for (&id, &info) in ids.iter().zip(infos) {
+        flocks.flocks.insert(id, info);
+    }
+
+    let mut events = vec![];
+    let mut sources: Vec<BTreeSet<FlockId>> = vec![BTreeSet::new(); infos.len()];
+    let mut successors: BTreeMap<FlockId, BTreeSet<FlockId>> = BTreeMap::new();
+    for &(c, old_id, _) in &links {
+        sources[c].insert(old_id);
+        successors.entry(old_id).or_default().insert(ids[c]);
+    }
+    for &old_id in old_flocks.keys() {
+        match successors.get(&old_id) {
+            None => events.push(FlockEvent::Death {
+                tick,
+                flock: summary(&old_flocks, old_id),
+            }),
+            Some(into) if into.len() > 1 => events.push(FlockEvent::Split {
+                tick,
+                from: summary(&old_flocks, old_id),
+                into: into.iter().map(|&id| summary(&flocks.flocks, id)).collect(),
+            }),
+            _ => {}
+        }
+    }
+    for (from, &id) in sources.iter().zip(&ids) {
+        match from.len() {
+            0 => events.push(FlockEvent::Birth {
+                tick,
+                flock: summary(&flocks.flocks, id),
+            }),
+            1 => {}
+            _ => events.push(FlockEvent::Merge {
+                tick,
+                from: from.iter().map(|&id| summary(&old_flocks, id)).collect(),
+                into: summary(&flocks.flocks, id),
+            }),
+        }
+    }
+    (ids, events)
+}
+
+/**
+* @param commands: Commands - Used to give boids a FlockId and take it away
+* @param boids: Query<(Entity, Option<&mut FlockId>), With<SpatialEntity>> - Query of all boids
+* @param store: Res<BoidStore> - Every flying boid
+* @param neighbours: Res<Neighbours> - The neighbour index over the store
+* @param flocks: ResMut<Flocks> - The flocks resource
+* @param values: Res<Values> - The values resource
+* @param bounds: Res<SimBounds> - Size of the simulated area, flocks can span the edges of a torus
+* @param tick: Res<SimTick> - Ticks run so far
+* @param events: EventWriter<FlockEvent> - The event writer for births, deaths, splits and merges
+* @description Groups the flying boids into flocks, boids that can see each other (ignoring their
+* field of view) directly or through a chain of other boids are in the same one, then track_flocks
+* matches them up with last tick's for their ids and the flock events. Perched boids aren't in any
+*
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn flock_system(
+    mut commands: Commands,
+    mut boids: Query<(Entity, Option<&mut FlockId>), With<SpatialEntity>>,
+    store: Res<BoidStore>,
+    neighbours: Res<Neighbours>,
+    mut flocks: ResMut<Flocks>,
+    values: Res<Values>,
+    bounds: Res<SimBounds>,
+    tick: Res<SimTick>,
+    mut events: EventWriter<FlockEvent>,
+) {
+    let topology = Topology::new(&bounds, &values.modes);
+    // In store order, which is the same every run. Boids evil_system just killed are still in the
+    // store but not in the query
+    let mut member_of: Vec<Option<usize>> = vec![None; store.len()];
+    let mut members = vec![];
+    for (slot, &entity) in store.entities().iter().enumerate() {
+        if let Ok((_, id)) = boids.get(entity) {
+            member_of[slot] = Some(members.len());
+            members.push((
+                entity,
+                store.front.positions[slot],
+                store.front.velocities[slot],
+                id.copied(),
+            ));
+        }
+    }
+
+    let pool = ComputeTaskPool::get();
+    let chunk_size = members.len().div_ceil(pool.thread_num()).max(1);
+    let links = pool.scope(|s| {
+        for (chunk, boids) in members.chunks(chunk_size).enumerate() {
+            let (index, member_of) = (neighbours.index(), &member_of);
+            let range = values.boid_vis_range;
+            s.spawn(async move {
+                let mut links = vec![];
+                let mut found = vec![];
+                for (offset, (_, position, ..)) in boids.iter().enumerate() {
+                    let i = chunk * chunk_size + offset;
+                    found.clear();
+                    for image in topology.images(*position, range) {
+                        index.within(image, range, &mut found);
+                    }
+                    for &slot in &found {
+                        match member_of[slot] {
+                            Some(j) if j > i => links.push((i, j)),
+                            _ => {}
+                        }
+                    }
+                }
+                links
+            });
+        }
+    });
+    let mut sets = UnionFind::new(members.len());
+    for (i, j) in links.into_iter().flatten() {
+        sets.union(i, j);
+    }
+
+    // Components by their smallest member, which keeps them in a repeatable order
+    let mut components: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..members.len() {
+        components.entry(sets.root(i)).or_default().push(i);
+    }
+    let components: Vec<Vec<usize>> = components
+        .into_values()
+        .filter(|component| component.len() >= values.flock_min_size.max(1))
+        .collect();
+
+    // Averaged as offsets from one of the boids so a flock straddling the edge of a torus doesn't
+    // end up centered in the middle of the world
+    let infos: Vec<FlockInfo> = components
+        .iter()
+        .map(|component| {
+            let size = component.len();
+            let anchor = members[component[0]].1;
+            let centroid = topology.wrap(
+                anchor
+                    + component
+                        .iter()
+                        .map(|&i| topology.offset(anchor, members[i].1))
+                        .sum::<Vec2>()
+                        / size as f32,
+            );
+            let heading = component
+                .iter()
+                .map(|&i| members[i].2)
+                .sum::<Vec2>()
+                .normalize_or_zero();
+            FlockInfo {
+                size,
+                centroid,
+                heading,
+            }
+        })
+        .collect();
+    let previous: Vec<Vec<Option<FlockId>>> = components
+        .iter()
+        .map(|component| component.iter().map(|&i| members[i].3).collect())
+        .collect();
+
+    // This tick hasn't been counted yet
+    let (ids, tick_events) = track_flocks(&mut flocks, &previous, &infos, tick.0 + 1);
+    events.send_batch(tick_events);
+
+    let mut assigned: Vec<Option<FlockId>> = vec![None; members.len()];
+    for (component, &id) in components.iter().zip(&ids) {
+        for &i in component {
+            assigned[i] = Some(id);
+        }
+    }
+
+    // Only touch the boids whose flock actually changed
+    for ((entity, .., previous), assigned) in members.iter().zip(assigned) {
+        match (previous, assigned) {
+            (Some(previous), Some(assigned)) if *previous != assigned => {
+                if let Ok((.., Some(mut id))) = boids.get_mut(*entity) {
+                    *id = assigned;
+                }
+            }
+            (None, Some(assigned)) => {
+                commands.entity(*entity).insert(assigned);
+            }
+            (Some(_), None) => {
+                commands.entity(*entity).remove::<FlockId>();
+            }
+            _ => {}
+        }
+    }
+    for (entity, id) in boids.iter() {
+        if id.is_some() && store.slot(entity).is_none() {
+            commands.entity(entity).remove::<FlockId>();
+        }
+    }
+}
+
+/// Writes every [`FlockEvent`] to a file, one JSON object per line
+#[derive(Resource)]
+pub struct FlockEventLog {
+    out: BufWriter<File>,
+}
+
+impl FlockEventLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn write(&mut self, event: &FlockEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        writeln!(self.out)
+    }
+}
+
+/// Writes this tick's flock events to the [`FlockEventLog`], if there is one
+pub fn flock_event_log_system(
+    log: Option<ResMut<FlockEventLog>>,
+    mut commands: Commands,
+    mut events: EventReader<FlockEvent>,
+) {
+    let Some(mut log) = log else {
+        return;
+    };
+    for event in events.read() {
+        if let Err(err) = log.write(event) {
+            error!("writing flock events failed, stopping: {err}");
+            commands.remove_resource::<FlockEventLog>();
+            return;
+        }
+    }
+}
+
+pub fn flush_flock_event_log_system(
+    mut exit: EventReader<AppExit>,
+    log: Option<ResMut<FlockEventLog>>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+    if let Some(mut log) = log {
+        if let Err(err) = log.out.flush() {
+            error!("couldn't finish writing flock events: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(size: usize) -> FlockInfo {
+        FlockInfo {
+            size,
+            centroid: Vec2::ZERO,
+            heading: Vec2::X,
+        }
+    }
+
+    #[test]
+    fn one_boid_changing_flocks_is_not_a_split_or_merge() {
+        let mut flocks = Flocks::default();
+        let (ids, events) = track_flocks(
+            &mut flocks,
+            &[vec![None; 10], vec![None; 10]],
+            &[info(10), info(10)],
+            1,
+        );
+        assert_eq!(events.len(), 2);
+        let (a, b) = (Some(ids[0]), Some(ids[1]));
+
+        // One boid flies over from the first flock to the second
+        let mut second = vec![b; 10];
+        second.push(a);
+        let (next, events) =
+            track_flocks(&mut flocks, &[vec![a; 9], second], &[info(9), info(11)], 2);
+        assert_eq!(next, ids);
+        assert_eq!(events, vec![]);
+    }
+
+    #[test]
+    fn halves_going_separate_ways_is_a_split() {
+        let mut flocks = Flocks::default();
+        let (ids, _) = track_flocks(&mut flocks, &[vec![None; 10]], &[info(10)], 1);
+        let a = Some(ids[0]);
+        let (next, events) = track_flocks(
+            &mut flocks,
+            &[vec![a; 6], vec![a; 4]],
+            &[info(6), info(4)],
+            2,
+        );
+        assert_eq!(next[0], ids[0]);
+        assert!(matches!(&events[..], [FlockEvent::Split { into, .. }] if into.len() == 2));
+    }
+}
@@ -1,6 +1,29 @@
 use bevy::prelude::*;
-use wasm_bindgen::prelude::*;
+use boid::{Integrator, Kernel};
+use neighbours::NeighbourBackend;
+use predator::PredatorStrategy;
+use rand::rngs::SmallRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{PI, TAU};
+pub mod analytics;
 pub mod boid;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
+pub mod evil;
+pub mod flock;
+pub mod metrics;
+pub mod neighbours;
+pub mod obstacle;
+pub mod perch;
+pub mod predator;
+pub mod presets;
+pub mod record;
+pub mod render;
+pub mod replay;
+pub mod sim;
+pub mod store;
+pub mod ui;
 pub mod web_ui;
 
 pub const CLEAR: Color = Color::srgb(0.0, 0.0, 0.0);
@@ -23,23 +46,153 @@ pub const MARGIN: f32 = 100.0;
 #[cfg(target_arch = "wasm32")]
 pub const BOUNDS: Vec2 = Vec2::new(WINDOW_WIDTH - 2.0 * MARGIN, WINDOW_HEIGHT - 2.0 * MARGIN);
 
+/// Size of the area the boids fly around in. Defaults to the window size, and is kept in sync
+/// with the primary window when rendering
 #[derive(Resource, Copy, Clone)]
+pub struct SimBounds(pub Vec2);
+
+impl Default for SimBounds {
+    fn default() -> Self {
+        SimBounds(Vec2::new(WINDOW_WIDTH, WINDOW_HEIGHT))
+    }
+}
+
+/// How positions relate to each other: a plain plane, or in toroidal mode a torus the size of
+/// [`SimBounds`] where leaving one edge brings you back in at the opposite one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Topology {
+    size: Vec2,
+    toroidal: bool,
+}
+
+impl Topology {
+    pub fn new(bounds: &SimBounds, modes: &Modes) -> Self {
+        Self {
+            size: bounds.0,
+            toroidal: modes.toroidal,
+        }
+    }
+
+    /// Size of the world
+    pub fn size(&self) -> Vec2 {
+        self.size
+    }
+
+    pub fn is_toroidal(&self) -> bool {
+        self.toroidal
+    }
+
+    /// Shortest vector from `from` to `to`, which on a torus might go across an edge
+    pub fn offset(&self, from: Vec2, to: Vec2) -> Vec2 {
+        let delta = to - from;
+        if self.toroidal {
+            delta - self.size * (delta / self.size).round()
+        } else {
+            delta
+        }
+    }
+
+    /// Center of mass of `positions`. On a torus that's the circular mean along each axis, so a
+    /// flock straddling an edge is centered on the edge and not in the middle of the world
+    pub fn center(&self, positions: impl Iterator<Item = Vec2>) -> Vec2 {
+        if !self.toroidal {
+            let (sum, count) = positions.fold((Vec2::ZERO, 0), |(sum, n), p| (sum + p, n + 1));
+            return if count == 0 {
+                Vec2::ZERO
+            } else {
+                sum / count as f32
+            };
+        }
+        // Every position as a pair of angles around the torus
+        let scale = TAU / self.size;
+        let (cos, sin) = positions.fold((Vec2::ZERO, Vec2::ZERO), |(cos, sin), p| {
+            let angle = p * scale;
+            (
+                cos + Vec2::new(angle.x.cos(), angle.y.cos()),
+                sin + Vec2::new(angle.x.sin(), angle.y.sin()),
+            )
+        });
+        self.wrap(Vec2::new(sin.x.atan2(cos.x), sin.y.atan2(cos.y)) / scale)
+    }
+
+    /// Puts a position that's left the torus back in on the other side
+    pub fn wrap(&self, position: Vec2) -> Vec2 {
+        if self.toroidal {
+            (position + self.size / 2.).rem_euclid(self.size) - self.size / 2.
+        } else {
+            position
+        }
+    }
+
+    /// `position`, and on a torus copies of it a whole world over for every edge it's within
+    /// `range` of. Searching around all of them finds neighbours on the far side of the seam that a
+    /// search around `position` alone would miss
+    pub fn images(&self, position: Vec2, range: f32) -> impl Iterator<Item = Vec2> {
+        let half = self.size / 2.;
+        let shifts = |p: f32, half: f32, size: f32| {
+            let near_low = self.toroidal && p < -half + range;
+            let near_high = self.toroidal && p > half - range;
+            [
+                Some(0.),
+                near_low.then_some(size),
+                near_high.then_some(-size),
+            ]
+        };
+        let xs = shifts(position.x, half.x, self.size.x);
+        let ys = shifts(position.y, half.y, self.size.y);
+        ys.into_iter()
+            .flatten()
+            .flat_map(move |y| xs.into_iter().flatten().map(move |x| Vec2::new(x, y)))
+            .map(move |shift| position + shift)
+    }
+}
+
+/// World-space position of whatever the boids should chase (or flee from in predator mode).
+/// Only present while there is a cursor to follow, headless runs can insert it by hand
+#[derive(Resource, Copy, Clone)]
+pub struct CursorPosition(pub Vec2);
+
+/// Seed for every random number the simulation draws. The same seed and [`Values`] give the
+/// exact same run, insert it before the app starts to reproduce one
+#[derive(Resource, Copy, Clone, Debug)]
+pub struct SimSeed(pub u64);
+
+impl Default for SimSeed {
+    fn default() -> Self {
+        SimSeed(rand::thread_rng().gen())
+    }
+}
+
+/// The simulation's random number generator, seeded from [`SimSeed`] at startup. Anything random
+/// in the simulation has to come from here, never from `thread_rng`
+#[derive(Resource)]
+pub struct SimRng(pub SmallRng);
+
+#[derive(Resource, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Modes {
     pub paused: bool,
     pub mouse_predator: bool,
     pub color_mode: bool,
     pub color_flocking: bool,
+    /// Boids that fly into the bottom edge land there for a while. A torus has no bottom edge, so
+    /// this does nothing while `toroidal` is on
     pub perching: bool,
     pub toroidal: bool,
+    /// Boids that are left alone for too long turn on the flock and start hunting it
+    pub evil: bool,
 }
 
-#[derive(Resource, Copy, Clone)]
+/// Every knob the simulation has. Presets are this serialized, anything a preset leaves out keeps
+/// its default
+#[derive(Resource, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Values {
     /// Number of boids to spawn
     pub boid_count: i32,
     /// Size of the boids
     pub boid_size: f32,
-    /// Speed of the boids
+    /// Speed the boids start out at, in units per second
     pub boid_speed: f32,
     ///Maximum number of neighbors a boid can have
     pub max_neighbors: usize,
@@ -48,50 +201,194 @@ pub struct Values {
     /// Protection range of the boids, determines how far away a boid can see a predator/obstacle
     /// or a boid that is determined to be "too close"
     pub boid_prot_range: f32,
-    /// Factor/amount that the boids want to center around the center of mass of the boids
+    /// Factor/amount that the boids want to center around the center of mass of the boids. Like the
+    /// other steering factors it's an acceleration, per second squared for the ones that scale a
+    /// distance and per second for the ones that scale a velocity
     pub boid_centering_factor: f32,
     /// Factor/amount that the boids want to avoid each other
     pub boid_avoidance_factor: f32,
     /// Factor/amount that the boids want to match the velocity of the boids around them
     pub boid_matching_factor: f32,
-    /// Minimum speed of the boids
+    /// Minimum speed of the boids, in units per second
     pub boid_min_speed: f32,
-    /// Maximum speed of the boids
+    /// Maximum speed of the boids, in units per second
     pub boid_max_speed: f32,
-    ///boid field of view
+    /// Full angle of the cone a boid flocks with others in, in radians, centred on the way it's
+    /// flying
     pub boid_fov: f32,
+    /// Full angle of the cone straight behind a boid that it can't see anything in, hunters
+    /// included, in radians. 0 for none
+    pub boid_blind_spot: f32,
+    /// How much a neighbour at the edge of the field of view counts next to one dead ahead, 1 for
+    /// all the same
+    pub boid_peripheral_weight: f32,
 
+    /// Square of `boid_vis_range`, kept in sync with it by the simulation
+    #[serde(skip)]
     pub vis_range_sq: f32,
+    /// Square of `boid_prot_range`, kept in sync with it by the simulation
+    #[serde(skip)]
     pub prot_range_sq: f32,
+    /// Cosine of the widest angle off the heading still in the field of view, kept in sync with
+    /// `boid_fov` by the simulation
+    #[serde(skip)]
+    pub fov_cos: f32,
+    /// Cosine of the widest angle off the heading outside the blind spot, kept in sync with
+    /// `boid_blind_spot` by the simulation
+    #[serde(skip)]
+    pub blind_spot_cos: f32,
 
     pub boid_mouse_chase_factor: f32,
     pub boid_bound_size: f32,
+    /// Acceleration, in units per second squared, that turns boids back from the border
     pub boid_turn_factor: f32,
 
+    /// Shortest time in seconds a boid stays on the ground once it lands, in perching mode
+    pub boid_perch_min_time: f32,
+    /// Longest time in seconds a boid stays on the ground once it lands, in perching mode
+    pub boid_perch_max_time: f32,
+
+    /// How quickly a boid's color blends towards the average of its neighbours in color mode, per
+    /// second. The difference shrinks by a factor of e every `1 / rate` seconds
+    pub boid_color_blend_rate: f32,
+    /// How quickly a boid's color drifts back to its start color when it's alone in color mode, per
+    /// second like `boid_color_blend_rate`
+    pub boid_color_revert_rate: f32,
+    /// Seconds a boid has to spend without any neighbours before it picks a new random color
+    pub boid_lonely_time: f32,
+    /// How much boids ignore differently colored neighbours when centering and matching velocity
+    /// in color flocking mode, from 0 (colorblind) to 1 (only their own hue counts)
+    pub boid_color_affinity: f32,
+    /// Factor/amount that boids steer away from neighbours with a dissimilar hue in color flocking
+    /// mode, 0 turns the repulsion off
+    pub boid_color_repulsion: f32,
+
+    /// Seconds a boid has to spend without any neighbours before it turns evil, in evil mode
+    pub boid_evil_time: f32,
+    /// Factor/amount that evil boids steer towards their prey
+    pub boid_pursuit_factor: f32,
+    /// Factor/amount that boids steer away from evil boids they can see
+    pub boid_flee_factor: f32,
+    /// How close an evil boid has to get to its prey to kill it
+    pub boid_kill_radius: f32,
+    /// Seconds an evil boid has to wait after a kill before it can kill again
+    pub boid_kill_cooldown: f32,
+
+    /// Factor/amount that boids steer away from predators within their protection range
+    pub boid_scatter_factor: f32,
+    /// Number of predators hunting the flock
+    pub predator_count: i32,
+    /// Speed of the predators in units per second, they always fly flat out
+    pub predator_speed: f32,
+    /// Most a predator can turn in one second, in radians
+    pub predator_turn_rate: f32,
+    /// How far away a predator can size up boids when looking for the most isolated one
+    pub predator_vision_range: f32,
+    /// How predators pick what to chase
+    pub predator_strategy: PredatorStrategy,
+
+    /// How many seconds ahead boids look along their velocity for obstacles
+    pub obstacle_lookahead: f32,
+    /// How close to an obstacle boids are comfortable getting before they steer away
+    pub obstacle_margin: f32,
+    /// Factor/amount that boids steer away from obstacles in their way
+    pub obstacle_avoidance_factor: f32,
+
+    /// Fewest boids a group needs to count as a flock
+    pub flock_min_size: usize,
+
+    /// How velocities and positions are stepped forward each tick
+    pub integrator: Integrator,
+    /// How boids find the others within `boid_vis_range`
+    pub neighbour_backend: NeighbourBackend,
+
+    /// How separation weighs the boids in the protected range by distance
+    pub separation_kernel: Kernel,
+    /// How alignment weighs the boids in view by distance
+    pub alignment_kernel: Kernel,
+    /// How cohesion weighs the boids in view by distance
+    pub cohesion_kernel: Kernel,
+    /// How many of the nearest boids a topological kernel pays attention to
+    pub boid_topological_neighbors: usize,
+
     pub modes: Modes,
 }
 
+impl Values {
+    /// Brings the fields that are worked out from other fields back in line with them. Returns
+    /// whether anything had to change
+    pub fn update_derived(&mut self) -> bool {
+        let vis_range_sq = self.boid_vis_range * self.boid_vis_range;
+        let prot_range_sq = self.boid_prot_range * self.boid_prot_range;
+        // Both reach at most right behind the boid, where the cosine bottoms out
+        let fov_cos = (self.boid_fov / 2.).min(PI).cos();
+        let blind_spot_cos = (PI - self.boid_blind_spot / 2.).clamp(0., PI).cos();
+        let stale = self.vis_range_sq != vis_range_sq
+            || self.prot_range_sq != prot_range_sq
+            || self.fov_cos != fov_cos
+            || self.blind_spot_cos != blind_spot_cos;
+        self.vis_range_sq = vis_range_sq;
+        self.prot_range_sq = prot_range_sq;
+        self.fov_cos = fov_cos;
+        self.blind_spot_cos = blind_spot_cos;
+        stale
+    }
+}
+
 impl Default for Values {
     #[cfg(not(target_arch = "wasm32"))]
     fn default() -> Self {
         Self {
             boid_count: 1500,
             boid_size: 0.4,
-            boid_speed: 5.,
+            boid_speed: 300.,
             max_neighbors: 100,
             boid_vis_range: 35.0,
-            boid_fov: 120.0 * std::f32::consts::PI / 180.0,
+            boid_fov: 240.0 * std::f32::consts::PI / 180.0,
+            boid_blind_spot: 0.0,
+            boid_peripheral_weight: 1.0,
             boid_bound_size: 98.0,
-            boid_turn_factor: 0.5,
+            boid_turn_factor: 1800.0,
             boid_prot_range: 10.0,
-            boid_centering_factor: 0.0008,
-            boid_mouse_chase_factor: 0.0006,
-            boid_avoidance_factor: 0.05,
-            boid_matching_factor: 0.05,
-            boid_min_speed: 5.,
-            boid_max_speed: 10.,
+            boid_centering_factor: 2.88,
+            boid_mouse_chase_factor: 2.16,
+            boid_avoidance_factor: 180.0,
+            boid_matching_factor: 3.0,
+            boid_min_speed: 300.,
+            boid_max_speed: 600.,
             vis_range_sq: 35.0 * 35.0,
             prot_range_sq: 10.0 * 10.0,
+            // 120° to either side, and no blind spot
+            fov_cos: -0.5,
+            blind_spot_cos: -1.0,
+            boid_perch_min_time: 1.0,
+            boid_perch_max_time: 4.0,
+            boid_color_blend_rate: 6.3,
+            boid_color_revert_rate: 9.75,
+            boid_lonely_time: 5.0,
+            boid_color_affinity: 0.8,
+            boid_color_repulsion: 1.8,
+            boid_evil_time: 10.0,
+            boid_pursuit_factor: 3.0,
+            boid_flee_factor: 288.0,
+            boid_kill_radius: 6.0,
+            boid_kill_cooldown: 2.0,
+            boid_scatter_factor: 1800.0,
+            predator_count: 0,
+            predator_speed: 480.,
+            predator_turn_rate: 6.0,
+            predator_vision_range: 120.0,
+            predator_strategy: PredatorStrategy::Nearest,
+            obstacle_lookahead: 0.133,
+            obstacle_margin: 20.0,
+            obstacle_avoidance_factor: 5400.0,
+            flock_min_size: 3,
+            integrator: Integrator::SemiImplicitEuler,
+            neighbour_backend: NeighbourBackend::Grid,
+            separation_kernel: Kernel::Uniform,
+            alignment_kernel: Kernel::Uniform,
+            cohesion_kernel: Kernel::Uniform,
+            boid_topological_neighbors: 7,
             modes: Modes {
                 paused: false,
                 mouse_predator: false,
@@ -99,6 +396,7 @@ impl Default for Values {
                 color_flocking: false,
                 perching: false,
                 toroidal: false,
+                evil: false,
             },
         }
     }
@@ -108,21 +406,54 @@ impl Default for Values {
         Self {
             boid_count: 108,
             boid_size: 1.,
-            boid_speed: 3.5,
+            boid_speed: 210.,
             max_neighbors: 20,
             boid_vis_range: 25.0,
-            boid_fov: 120.0 * std::f32::consts::PI / 180.0,
+            boid_fov: 240.0 * std::f32::consts::PI / 180.0,
+            boid_blind_spot: 0.0,
+            boid_peripheral_weight: 1.0,
             boid_bound_size: 98.0,
-            boid_turn_factor: 0.75,
+            boid_turn_factor: 2700.0,
             boid_prot_range: 10.0,
-            boid_centering_factor: 0.0004,
-            boid_mouse_chase_factor: 0.0004,
-            boid_avoidance_factor: 0.05,
-            boid_matching_factor: 0.05,
-            boid_min_speed: 3.,
-            boid_max_speed: 7.,
+            boid_centering_factor: 1.44,
+            boid_mouse_chase_factor: 1.44,
+            boid_avoidance_factor: 180.0,
+            boid_matching_factor: 3.0,
+            boid_min_speed: 180.,
+            boid_max_speed: 420.,
             vis_range_sq: 25.0 * 25.0, // Updated to match new boid_vis_range
             prot_range_sq: 10.0 * 10.0,
+            // 120° to either side, and no blind spot
+            fov_cos: -0.5,
+            blind_spot_cos: -1.0,
+            boid_perch_min_time: 1.0,
+            boid_perch_max_time: 4.0,
+            boid_color_blend_rate: 6.3,
+            boid_color_revert_rate: 9.75,
+            boid_lonely_time: 5.0,
+            boid_color_affinity: 0.8,
+            boid_color_repulsion: 1.8,
+            boid_evil_time: 10.0,
+            boid_pursuit_factor: 3.0,
+            boid_flee_factor: 288.0,
+            boid_kill_radius: 6.0,
+            boid_kill_cooldown: 2.0,
+            boid_scatter_factor: 1800.0,
+            predator_count: 0,
+            predator_speed: 480.,
+            predator_turn_rate: 6.0,
+            predator_vision_range: 120.0,
+            predator_strategy: PredatorStrategy::Nearest,
+            obstacle_lookahead: 0.133,
+            obstacle_margin: 20.0,
+            obstacle_avoidance_factor: 5400.0,
+            flock_min_size: 3,
+            integrator: Integrator::SemiImplicitEuler,
+            neighbour_backend: NeighbourBackend::Grid,
+            separation_kernel: Kernel::Uniform,
+            alignment_kernel: Kernel::Uniform,
+            cohesion_kernel: Kernel::Uniform,
+            boid_topological_neighbors: 7,
             modes: Modes {
                 paused: false,
                 mouse_predator: false,
@@ -130,49 +461,8 @@ impl Default for Values {
                 color_flocking: false,
                 perching: false,
                 toroidal: false,
+                evil: false,
             },
         }
     }
 }
-
-#[derive(Resource, Default)]
-pub struct FpsCounter {
-    fps: f64,
-}
-
-pub fn update_fps_counter(time: Res<Time>, mut fps_counter: ResMut<FpsCounter>) {
-    fps_counter.fps = 1.0 / time.delta_seconds_f64();
-}
-
-// pub fn display_fps(
-//     mut commands: Commands,
-//     fps_counter: Res<FpsCounter>,
-//     mut query: Query<Entity, With<FpsText>>,
-// ) {
-//     let fps_text = format!("FPS: {:.2}", fps_counter.fps);
-
-//     if let Ok(entity) = query.get_single_mut() {
-//         commands.entity(entity).despawn();
-//     }
-
-//     commands.spawn((
-//         TextBundle::from_section(
-//             fps_text,
-//             TextStyle {
-//                 font_size: 20.0,
-//                 color: Color::WHITE,
-//                 ..default()
-//             },
-//         )
-//         .with_style(Style {
-//             position_type: PositionType::Absolute,
-//             top: Val::Px(10.0),
-//             left: Val::Px(10.0),
-//             ..default()
-//         }),
-//         FpsText,
-//     ));
-// }
-
-#[derive(Component)]
-pub struct FpsText;
@@ -1,12 +1,18 @@
 use bevy::prelude::*;
-use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use bevy_spatial::{AutomaticUpdate, SpatialStructure};
-use boids::boid::*;
-use boids::Values;
-use boids::BOUNDS;
-use boids::WINDOW_HEIGHT;
-use boids::WINDOW_WIDTH;
-use std::time::Duration;
+use bevy_egui::EguiPlugin;
+use boids::analytics::analytics_system;
+use boids::flock::TrackFlocks;
+use boids::obstacle::demo_obstacles;
+use boids::render::BoidsRenderPlugin;
+use boids::sim::BoidsSimPlugin;
+use boids::ui::{flock_log_system, keyboard_system, ui_system};
+#[cfg(target_arch = "wasm32")]
+use boids::{WINDOW_HEIGHT, WINDOW_WIDTH};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    bevy::app::ScheduleRunnerPlugin, bevy::log::LogPlugin, bevy::time::TimeUpdateStrategy,
+    boids::cli::Cli, boids::replay::ReplayPlugin, boids::SimBounds, std::time::Duration,
+};
 // NOTE: The below code is ALSO really important for a rust-wasm binary to work. I am stupid and
 // did not realize this
 #[cfg(target_arch = "wasm32")]
@@ -14,46 +20,75 @@ use wasm_bindgen::prelude::*;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn main() {
-    (App::new().add_plugins((
-        DefaultPlugins.set(WindowPlugin {
-            primary_window: Some(Window {
-                canvas: Some("#bevy_boids_canvas".into()),
-                resizable: true,
-                ..default()
-            }),
+    let cli = Cli::parse_and_validate();
+    let mut app = App::new();
+
+    if cli.headless {
+        // One tick per loop, as quickly as they can be run
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::ZERO)))
+            .add_plugins(LogPlugin::default())
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1.0 / cli.tick_rate,
+            )))
+            .add_plugins(BoidsSimPlugin);
+    } else {
+        let mut window = Window {
+            canvas: Some("#bevy_boids_canvas".into()),
+            resizable: true,
+            ..default()
+        };
+        let replay = cli.replay();
+        // Replays open at the size of the world they were recorded in
+        let size = match &replay {
+            Some(replay) => replay.recording().bounds(),
+            None => Vec2::new(window.width(), window.height()),
+        };
+        if replay.is_some() || cli.width.is_some() || cli.height.is_some() {
+            let size = cli.size(size);
+            window.resolution = (size.x, size.y).into();
+        }
+        let window_size = Vec2::new(window.width(), window.height());
+        app.add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(window),
             ..default()
-        }),
-        // Track boids in the KD-Tree
-        AutomaticUpdate::<SpatialEntity>::new()
-            .with_spatial_ds(SpatialStructure::KDTree2)
-            .with_frequency(Duration::from_millis(16)),
-    )))
-    .add_plugins(EguiPlugin)
-    .insert_resource(Values::default())
-    .insert_resource(Time::<Fixed>::from_hz(60.0))
-    .add_event::<DvEvent>()
-    .add_event::<ColorEvent>() // event for changing the color of the boids
-    .add_systems(Startup, boid_setup)
-    .add_systems(Update, ui_system)
-    .add_systems(
-        FixedUpdate,
-        (
-            velo_system,
-            movement_system,
-            flocking_system,
-            color_change_system,
-        ),
-    )
-    //.add_systems(Update, ui_system)
-    .run();
+        }));
+        match replay {
+            Some(replay) => app.insert_resource(replay).add_plugins(ReplayPlugin),
+            // Something to look at and fly around, headless runs stay obstacle free. The bounds
+            // follow the window once it's open, the first boids are spawned before that
+            None => app
+                .add_plugins(BoidsSimPlugin)
+                .insert_resource(SimBounds(window_size))
+                .add_systems(Startup, demo_obstacles),
+        };
+        // The flock event window needs flocks tracked
+        app.add_plugins(BoidsRenderPlugin)
+            .add_plugins(EguiPlugin)
+            .insert_resource(TrackFlocks)
+            .add_systems(
+                Update,
+                (
+                    ui_system,
+                    keyboard_system,
+                    analytics_system,
+                    flock_log_system,
+                ),
+            );
+    }
+
+    // A replay has everything it needs in the recording
+    if cli.replay.is_none() {
+        cli.apply(&mut app);
+    }
+    app.run();
 }
 #[cfg(target_arch = "wasm32")]
 fn main() {
     web_sys::console::log_1(&"Initializing WASM application...".into());
     // Redirect `log` message to `console.log` and friends:
     //eframe::WebLogger::init(log::LevelFilter::Debug).ok();
-    (App::new().add_plugins((
-        DefaultPlugins.set(WindowPlugin {
+    App::new()
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 canvas: Some("#bevy_boids_canvas".into()),
                 resizable: false,
@@ -62,40 +97,25 @@ fn main() {
                 ..default()
             }),
             ..default()
-        }),
-        // Track boids in the KD-Tree
-        AutomaticUpdate::<SpatialEntity>::new()
-            .with_spatial_ds(SpatialStructure::KDTree2)
-            .with_frequency(Duration::from_millis(16)),
-    )))
-    .add_plugins(EguiPlugin)
-    .insert_resource(Values::default())
-    .insert_resource(Time::<Fixed>::from_hz(60.0))
-    .add_event::<DvEvent>()
-    .add_systems(Startup, boid_setup)
-    .add_systems(Update, ui_system)
-    //.add_systems(Update, update_fps_counter)
-    .add_systems(FixedUpdate, (velo_system, movement_system, flocking_system))
-    .run();
+        }))
+        .add_plugins((BoidsSimPlugin, BoidsRenderPlugin))
+        .add_plugins(EguiPlugin)
+        .insert_resource(TrackFlocks)
+        .add_systems(Startup, demo_obstacles)
+        .add_systems(
+            Update,
+            (
+                ui_system,
+                keyboard_system,
+                analytics_system,
+                flock_log_system,
+            ),
+        )
+        .run();
 }
 
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen(start)]
 pub fn start() {
     main();
 }
-
-pub fn ui_system(mut egui_context: EguiContexts, mut values: ResMut<Values>) {
-    let ctx = &mut egui_context.ctx_mut();
-    egui::Window::new("Settings")
-        .resizable(true)
-        .collapsible(true)
-        .open(&mut true)
-        .default_open(true)
-        .show(ctx, |ui| {
-            ui.label("Application Settings");
-            ui.horizontal(|ui| {
-                ui.label("Number of Boids");
-                ui.add(egui::Slider::new(&mut values.boid_count, 1..=150));
-            });
-        });
-}
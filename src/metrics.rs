@@ -0,0 +1,292 @@
+use crate::boid::{SpatialEntity, Velocity};
+use crate::flock::UnionFind;
+use crate::neighbours::{NeighbourIndex, SpatialHashGrid};
+use crate::sim::SimTick;
+use crate::{SimBounds, Topology, Values};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The usual collective motion measures, for the whole flock at one tick
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlockMetrics {
+    pub tick: u64,
+    pub boids: usize,
+    pub mean_speed: f32,
+    /// How aligned the boids are, from 0 (every which way) to 1 (all heading the same way)
+    pub polarization: f32,
+    /// How much the flock circles its own center of mass, from 0 to 1 for a perfect mill
+    pub milling: f32,
+    /// Distribution of the distance from each boid to the one closest to it
+    pub nearest_min: f32,
+    pub nearest_mean: f32,
+    pub nearest_median: f32,
+    pub nearest_p90: f32,
+    pub nearest_max: f32,
+    /// Groups of boids that are linked by chains of boids within seeing distance of each other,
+    /// lone boids count as a cluster of their own
+    pub clusters: usize,
+    pub largest_cluster: usize,
+}
+
+impl FlockMetrics {
+    pub const CSV_HEADER: &'static str = "tick,boids,mean_speed,polarization,milling,\
+        nearest_min,nearest_mean,nearest_median,nearest_p90,nearest_max,clusters,largest_cluster";
+
+    /// Measures a flock given as (position, velocity) pairs. Boids closer than `link_range` to each
+    /// other end up in the same cluster. Distances are measured the way `topology` says, so on a
+    /// torus a flock straddling an edge is still one flock
+    pub fn measure(tick: u64, boids: &[(Vec2, Vec2)], link_range: f32, topology: Topology) -> Self {
+        let count = boids.len();
+        if count == 0 {
+            return Self { tick, ..default() };
+        }
+        let n = count as f32;
+
+        let speed_sum: f32 = boids.iter().map(|(_, v)| v.length()).sum();
+        let center = topology.center(boids.iter().map(|(p, _)| *p));
+        let (angular, angular_max) = boids.iter().fold((0., 0.), |(sum, max), (p, v)| {
+            let r = topology.offset(center, *p);
+            (sum + r.perp_dot(*v), max + r.length() * v.length())
+        });
+
+        // The same grid the boids find each other with
+        let points: Vec<Vec2> = boids.iter().map(|(p, _)| *p).collect();
+        let range = link_range.max(1.);
+        let mut index = SpatialHashGrid::default();
+        index.rebuild(&points, range);
+
+        // Nothing can be further apart than this, halfway round a torus or across the flock
+        let furthest = if topology.is_toroidal() {
+            topology.size().length() / 2.
+        } else {
+            let (min, max) = points.iter().fold((points[0], points[0]), |(min, max), p| {
+                (min.min(*p), max.max(*p))
+            });
+            min.distance(max)
+        };
+        let mut found = vec![];
+        let mut nearest: Vec<f32> = (0..count)
+            .map(|i| nearest_distance(&index, &points, i, range, furthest, topology, &mut found))
+            .collect();
+        nearest.sort_unstable_by(f32::total_cmp);
+        let percentile = |p: f32| nearest[((count - 1) as f32 * p).round() as usize];
+        let (clusters, largest_cluster) = clusters(&index, &points, range, topology, &mut found);
+
+        Self {
+            tick,
+            boids: count,
+            mean_speed: speed_sum / n,
+            polarization: polarization(boids.iter().map(|(_, v)| *v)),
+            milling: if angular_max > 0. {
+                angular.abs() / angular_max
+            } else {
+                0.
+            },
+            nearest_min: nearest[0],
+            nearest_mean: nearest.iter().sum::<f32>() / n,
+            nearest_median: percentile(0.5),
+            nearest_p90: percentile(0.9),
+            nearest_max: nearest[count - 1],
+            clusters,
+            largest_cluster,
+        }
+    }
+
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.tick,
+            self.boids,
+            self.mean_speed,
+            self.polarization,
+            self.milling,
+            self.nearest_min,
+            self.nearest_mean,
+            self.nearest_median,
+            self.nearest_p90,
+            self.nearest_max,
+            self.clusters,
+            self.largest_cluster
+        )
+    }
+}
+
+/// Length of the mean heading, 1 when every velocity points the same way. Boids that aren't moving
+/// don't count
+pub fn polarization(velocities: impl Iterator<Item = Vec2>) -> f32 {
+    let (sum, count) = velocities
+        .filter_map(|v| v.try_normalize())
+        .fold((Vec2::ZERO, 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        0.
+    } else {
+        sum.length() / count as f32
+    }
+}
+
+// Distance from point `i` to the closest other one, searching ever further out until something
+// turns up or there's nowhere left to look. 0 for a point that's alone
+fn nearest_distance(
+    index: &dyn NeighbourIndex,
+    points: &[Vec2],
+    i: usize,
+    range: f32,
+    furthest: f32,
+    topology: Topology,
+    found: &mut Vec<usize>,
+) -> f32 {
+    let mut radius = range;
+    loop {
+        found.clear();
+        for image in topology.images(points[i], radius) {
+            index.within(image, radius, found);
+        }
+        // Everything closer than the radius turned up, so the closest of those is the closest
+        let best = found
+            .iter()
+            .filter(|&&j| j != i)
+            .map(|&j| topology.offset(points[i], points[j]).length_squared())
+            .min_by(f32::total_cmp);
+        if let Some(best) = best {
+            return best.sqrt();
+        }
+        if radius > furthest {
+            return 0.;
+        }
+        radius *= 2.;
+    }
+}
+
+// Number of groups of points chained together by links shorter than `range`, and the size of the
+// biggest one
+fn clusters(
+    index: &dyn NeighbourIndex,
+    points: &[Vec2],
+    range: f32,
+    topology: Topology,
+    found: &mut Vec<usize>,
+) -> (usize, usize) {
+    let mut sets = UnionFind::new(points.len());
+    for (i, &point) in points.iter().enumerate() {
+        found.clear();
+        for image in topology.images(point, range) {
+            index.within(image, range, found);
+        }
+        for &j in found.iter() {
+            if j > i {
+                sets.union(i, j);
+            }
+        }
+    }
+
+    let mut sizes: HashMap<usize, usize> = HashMap::default();
+    for i in 0..points.len() {
+        *sizes.entry(sets.root(i)).or_default() += 1;
+    }
+    (sizes.len(), sizes.values().copied().max().unwrap_or(0))
+}
+
+/// Writes [`FlockMetrics`] to a CSV file every few ticks
+#[derive(Resource)]
+pub struct MetricsExporter {
+    out: BufWriter<File>,
+    /// Ticks between rows, 1 writes every tick
+    pub every: u64,
+}
+
+impl MetricsExporter {
+    pub fn create(path: &Path, every: u64) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", FlockMetrics::CSV_HEADER)?;
+        Ok(Self {
+            out,
+            every: every.max(1),
+        })
+    }
+
+    pub fn write(&mut self, metrics: &FlockMetrics) -> io::Result<()> {
+        writeln!(self.out, "{}", metrics.csv_row())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Measures the flock and writes a row to the [`MetricsExporter`], if there is one
+pub fn metrics_system(
+    exporter: Option<ResMut<MetricsExporter>>,
+    mut commands: Commands,
+    tick: Res<SimTick>,
+    values: Res<Values>,
+    bounds: Res<SimBounds>,
+    boids: Query<(Entity, &Transform, &Velocity), With<SpatialEntity>>,
+) {
+    let Some(mut exporter) = exporter else {
+        return;
+    };
+    if !tick.is_changed() || tick.0 == 0 || !tick.0.is_multiple_of(exporter.every) {
+        return;
+    }
+
+    // Sorted so the sums come out the same every run
+    let mut flock: Vec<_> = boids.iter().collect();
+    flock.sort_unstable_by_key(|(entity, ..)| *entity);
+    let flock: Vec<(Vec2, Vec2)> = flock
+        .into_iter()
+        .map(|(_, transform, velocity)| (transform.translation.truncate(), velocity.0))
+        .collect();
+
+    let topology = Topology::new(&bounds, &values.modes);
+    let metrics = FlockMetrics::measure(tick.0, &flock, values.boid_vis_range, topology);
+    if let Err(err) = exporter.write(&metrics) {
+        error!("writing metrics failed, stopping: {err}");
+        commands.remove_resource::<MetricsExporter>();
+    }
+}
+
+pub fn flush_metrics_system(
+    mut exit: EventReader<AppExit>,
+    exporter: Option<ResMut<MetricsExporter>>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+    if let Some(mut exporter) = exporter {
+        if let Err(err) = exporter.flush() {
+            error!("couldn't finish writing metrics: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Modes;
+
+    #[test]
+    fn flock_across_the_edge_of_a_torus_is_one_cluster() {
+        let bounds = SimBounds(Vec2::new(1000., 600.));
+        let topology = Topology::new(
+            &bounds,
+            &Modes {
+                toroidal: true,
+                ..default()
+            },
+        );
+        let boids = [
+            (Vec2::new(-496., 0.), Vec2::Y),
+            (Vec2::new(494., 0.), Vec2::Y),
+            (Vec2::new(490., 0.), Vec2::Y),
+        ];
+        let metrics = FlockMetrics::measure(1, &boids, 20., topology);
+        assert_eq!((metrics.clusters, metrics.largest_cluster), (1, 3));
+        assert!((metrics.nearest_max - 10.).abs() < 1e-3);
+        let center = topology.center(boids.iter().map(|(p, _)| *p));
+        assert!(topology.offset(center, Vec2::new(496., 0.)).length() < 1.);
+    }
+}
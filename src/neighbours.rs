@@ -0,0 +1,370 @@
+use crate::store::BoidStore;
+use crate::{Topology, Values};
+use bevy::prelude::*;
+use kd_tree::KdTree;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// Finds the points close to a point. Rebuilt from scratch every tick, so it only has to be quick
+/// to build and quick to ask, never updated in place
+pub trait NeighbourIndex: Send + Sync {
+    /// Indexes `positions`, forgetting whatever was indexed before. `radius` is the radius most
+    /// searches are going to use, for indexes that can make use of knowing it
+    fn rebuild(&mut self, positions: &[Vec2], radius: f32);
+
+    /// Adds the index into `positions` of every point closer than `radius` to `point` onto `out`,
+    /// in no particular order
+    fn within(&self, point: Vec2, radius: f32, out: &mut Vec<usize>);
+}
+
+/// Which [`NeighbourIndex`] boids find each other with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NeighbourBackend {
+    /// [`KdTreeIndex`]
+    KdTree,
+    /// [`SpatialHashGrid`]
+    #[default]
+    Grid,
+}
+
+impl NeighbourBackend {
+    fn index(self) -> Box<dyn NeighbourIndex> {
+        match self {
+            NeighbourBackend::KdTree => Box::<KdTreeIndex>::default(),
+            NeighbourBackend::Grid => Box::<SpatialHashGrid>::default(),
+        }
+    }
+}
+
+// The one test for being close enough that every index uses, so they agree on points right at the
+// edge of the radius
+fn in_range(position: Vec2, point: Vec2, radius_sq: f32) -> bool {
+    position.distance_squared(point) < radius_sq
+}
+
+/// A KD-tree over the points, good at any search radius
+#[derive(Default)]
+pub struct KdTreeIndex {
+    tree: KdTree<([f32; 2], usize)>,
+}
+
+impl NeighbourIndex for KdTreeIndex {
+    fn rebuild(&mut self, positions: &[Vec2], _radius: f32) {
+        let points = positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.to_array(), i))
+            .collect();
+        self.tree = KdTree::build_by_ordered_float(points);
+    }
+
+    fn within(&self, point: Vec2, radius: f32, out: &mut Vec<usize>) {
+        if self.tree.is_empty() {
+            return;
+        }
+        let radius_sq = radius * radius;
+        let corners = [(point - radius).to_array(), (point + radius).to_array()];
+        out.extend(
+            self.tree
+                .within(&corners)
+                .into_iter()
+                .filter(|(p, _)| in_range(Vec2::from(*p), point, radius_sq))
+                .map(|(_, i)| *i),
+        );
+    }
+}
+
+thread_local! {
+    // The search that last looked in each bucket of a SpatialHashGrid. One per thread, as searches
+    // run on several at once
+    static SEARCHED: RefCell<(u32, Vec<u32>)> = const { RefCell::new((0, vec![])) };
+}
+
+/// Square cells the size of the search radius, hashed into a table about twice as long as there
+/// are points. A search looks at the few cells its circle overlaps, so it costs about the same
+/// however many points there are, but a much bigger radius than the cells means a lot of cells.
+///
+/// The points are counting sorted by bucket, so the whole grid is a few flat arrays that get
+/// reused from one rebuild to the next. Cells next to each other along a row hash to buckets next
+/// to each other, so a search mostly reads one stretch of memory per row of cells.
+pub struct SpatialHashGrid {
+    cell_size: f32,
+    /// Where each bucket's points start in `entries`, with one extra at the end
+    starts: Vec<u32>,
+    /// Indices of the points, grouped by bucket
+    entries: Vec<u32>,
+    /// Where the point in the same place in `entries` is
+    points: Vec<Vec2>,
+}
+
+impl Default for SpatialHashGrid {
+    fn default() -> Self {
+        Self {
+            cell_size: 1.,
+            starts: vec![0, 0],
+            entries: vec![],
+            points: vec![],
+        }
+    }
+}
+
+impl SpatialHashGrid {
+    fn cell(&self, point: Vec2) -> IVec2 {
+        (point / self.cell_size).floor().as_ivec2()
+    }
+
+    fn bucket(&self, cell: IVec2) -> usize {
+        // Rows start a large prime apart, in a table whose length is a power of two
+        let hash = (cell.x as u32).wrapping_add((cell.y as u32).wrapping_mul(19_349_663));
+        hash as usize & (self.starts.len() - 2)
+    }
+}
+
+impl NeighbourIndex for SpatialHashGrid {
+    fn rebuild(&mut self, positions: &[Vec2], radius: f32) {
+        self.cell_size = radius.max(1.);
+        let buckets = (positions.len() * 2).next_power_of_two();
+        self.starts.clear();
+        self.starts.resize(buckets + 1, 0);
+
+        // Count the points in each bucket, turn the counts into where each bucket ends, then
+        // hand out slots from the back so each bucket ends up starting where it should
+        let buckets: Vec<usize> = positions
+            .iter()
+            .map(|p| self.bucket(self.cell(*p)))
+            .collect();
+        for &bucket in &buckets {
+            self.starts[bucket] += 1;
+        }
+        let mut total = 0;
+        for start in self.starts.iter_mut() {
+            total += *start;
+            *start = total;
+        }
+        self.entries.clear();
+        self.entries.resize(positions.len(), 0);
+        self.points.clear();
+        self.points.resize(positions.len(), Vec2::ZERO);
+        for (i, &bucket) in buckets.iter().enumerate().rev() {
+            self.starts[bucket] -= 1;
+            self.entries[self.starts[bucket] as usize] = i as u32;
+            self.points[self.starts[bucket] as usize] = positions[i];
+        }
+    }
+
+    fn within(&self, point: Vec2, radius: f32, out: &mut Vec<usize>) {
+        let (min, max) = (
+            self.cell(point - Vec2::splat(radius)),
+            self.cell(point + Vec2::splat(radius)),
+        );
+        let radius_sq = radius * radius;
+        // Every point gets written out and only the ones in range are kept, which points right
+        // around the edge of the range can't fool the branch predictor into slowing down
+        let scan = |entries: std::ops::Range<usize>, out: &mut Vec<usize>| {
+            let mut end = out.len();
+            out.resize(end + entries.len(), 0);
+            for (&i, &position) in self.entries[entries.clone()]
+                .iter()
+                .zip(&self.points[entries])
+            {
+                out[end] = i as usize;
+                end += in_range(position, point, radius_sq) as usize;
+            }
+            out.truncate(end);
+        };
+        let buckets = self.starts.len() - 1;
+
+        // A circle covering more cells than there are buckets might as well go through every
+        // bucket once, a vision range much bigger than the cells would otherwise take forever
+        let cells = (max.x as i64 - min.x as i64 + 1) * (max.y as i64 - min.y as i64 + 1);
+        if cells >= buckets as i64 {
+            scan(0..self.entries.len(), out);
+            return;
+        }
+
+        // The usual search is a few short rows, which only need checking against each other to
+        // be sure no bucket gets searched twice
+        let width = (max.x - min.x + 1) as usize;
+        let mut rows = [0; 4];
+        let height = (max.y - min.y + 1) as usize;
+        if height <= rows.len() {
+            for (row, y) in rows.iter_mut().zip(min.y..=max.y) {
+                *row = self.bucket(IVec2::new(min.x, y));
+            }
+            let rows = &rows[..height];
+            let apart = rows.iter().enumerate().all(|(i, &a)| {
+                a + width <= buckets && rows[..i].iter().all(|&b| a.abs_diff(b) >= width)
+            });
+            if apart {
+                for &row in rows {
+                    scan(
+                        self.starts[row] as usize..self.starts[row + width] as usize,
+                        out,
+                    );
+                }
+                return;
+            }
+        }
+
+        SEARCHED.with_borrow_mut(|(search, stamps)| {
+            if stamps.len() < buckets {
+                stamps.resize(buckets, 0);
+            }
+            *search = search.wrapping_add(1);
+            if *search == 0 {
+                stamps.fill(0);
+                *search = 1;
+            }
+            let run_entries = |run: std::ops::Range<usize>| {
+                self.starts[run.start] as usize..self.starts[run.end] as usize
+            };
+            for y in min.y..=max.y {
+                // A row of cells is a row of buckets, so it's one run of entries unless it wraps
+                // around the end of the table or runs into a bucket that's been searched already
+                let mut bucket = self.bucket(IVec2::new(min.x, y));
+                let mut run = bucket..bucket;
+                for _ in min.x..=max.x {
+                    // Cells that hash to the same bucket mustn't be searched twice
+                    if stamps[bucket] != *search {
+                        stamps[bucket] = *search;
+                        if run.end != bucket {
+                            scan(run_entries(run), out);
+                            run = bucket..bucket;
+                        }
+                        run.end += 1;
+                    }
+                    bucket = (bucket + 1) & (buckets - 1);
+                }
+                scan(run_entries(run), out);
+            }
+        });
+    }
+}
+
+/// The [`NeighbourIndex`] over the boids in the [`BoidStore`], by slot
+#[derive(Resource)]
+pub struct Neighbours {
+    backend: NeighbourBackend,
+    index: Box<dyn NeighbourIndex>,
+}
+
+impl Default for Neighbours {
+    fn default() -> Self {
+        let backend = NeighbourBackend::default();
+        Self {
+            backend,
+            index: backend.index(),
+        }
+    }
+}
+
+impl Neighbours {
+    pub fn index(&self) -> &dyn NeighbourIndex {
+        &*self.index
+    }
+
+    /// The slot closest to `point` that `accept` takes, across the edges of a torus too. Only
+    /// looks `radius` around it unless there's nothing there, then it goes through every slot.
+    /// Ties go to the lowest slot
+    pub fn nearest(
+        &self,
+        positions: &[Vec2],
+        point: Vec2,
+        radius: f32,
+        topology: Topology,
+        accept: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let mut found = vec![];
+        for image in topology.images(point, radius) {
+            self.index.within(image, radius, &mut found);
+        }
+        let distance = |slot: usize| {
+            let dist_sq = topology.offset(point, positions[slot]).length_squared();
+            (dist_sq.to_bits(), slot)
+        };
+        let in_range = found
+            .into_iter()
+            .filter(|&slot| accept(slot))
+            .map(distance)
+            .min();
+        in_range
+            .or_else(|| {
+                (0..positions.len())
+                    .filter(|&slot| accept(slot))
+                    .map(distance)
+                    .min()
+            })
+            .map(|(_, slot)| slot)
+    }
+}
+
+// Indexes where the boids in the store are at the start of the tick, switching backend first if
+// `neighbour_backend` has changed
+pub fn neighbour_index_system(
+    mut neighbours: ResMut<Neighbours>,
+    store: Res<BoidStore>,
+    values: Res<Values>,
+) {
+    if neighbours.backend != values.neighbour_backend {
+        *neighbours = Neighbours {
+            backend: values.neighbour_backend,
+            index: values.neighbour_backend.index(),
+        };
+    }
+    neighbours
+        .index
+        .rebuild(&store.front.positions, values.boid_vis_range);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Modes, SimBounds};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn kd_tree_and_grid_find_the_same_points() {
+        let topology = Topology::new(
+            &SimBounds(Vec2::new(800., 600.)),
+            &Modes {
+                toroidal: true,
+                ..default()
+            },
+        );
+        // Whole numbers put plenty of points exactly on the edge of a whole number radius
+        let mut rng = SmallRng::seed_from_u64(7);
+        let positions: Vec<Vec2> = (0..2000)
+            .map(|_| {
+                Vec2::new(
+                    rng.gen_range(-400..400) as f32,
+                    rng.gen_range(-300..300) as f32,
+                )
+            })
+            .collect();
+
+        let mut kd_tree = KdTreeIndex::default();
+        let mut grid = SpatialHashGrid::default();
+        let (mut from_kd_tree, mut from_grid) = (vec![], vec![]);
+        // Small cells make for searches across hundreds of them, and the last one covers more
+        // cells than the grid has buckets
+        for (cell_size, radius) in [(25., 5.), (25., 25.), (25., 60.), (4., 60.), (1., 500.)] {
+            kd_tree.rebuild(&positions, cell_size);
+            grid.rebuild(&positions, cell_size);
+            for &point in &positions {
+                from_kd_tree.clear();
+                from_grid.clear();
+                for image in topology.images(point, radius) {
+                    kd_tree.within(image, radius, &mut from_kd_tree);
+                    grid.within(image, radius, &mut from_grid);
+                }
+                from_kd_tree.sort_unstable();
+                from_grid.sort_unstable();
+                assert_eq!(
+                    from_kd_tree, from_grid,
+                    "around {point} within {radius} in cells of {cell_size}"
+                );
+            }
+        }
+    }
+}
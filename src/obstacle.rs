@@ -0,0 +1,175 @@
+use crate::{Topology, Values};
+use bevy::prelude::*;
+
+/// Something solid boids have to fly around. Placed and rotated by its [`Transform`]
+#[derive(Component, Clone, Debug)]
+pub struct Obstacle(pub ObstacleShape);
+
+/// The outline of an [`Obstacle`], in its local space
+#[derive(Clone, Debug)]
+pub enum ObstacleShape {
+    Circle {
+        radius: f32,
+    },
+    Rect {
+        half_size: Vec2,
+    },
+    /// Any simple polygon, the points go around the outline in order. Only convex ones are drawn
+    /// correctly, but boids avoid the exact shape either way
+    Polygon {
+        points: Vec<Vec2>,
+    },
+}
+
+impl ObstacleShape {
+    /// Signed distance from `point` to the outline, negative inside the shape, and the direction
+    /// that leads out of it
+    pub fn distance(&self, point: Vec2) -> (f32, Vec2) {
+        match self {
+            ObstacleShape::Circle { radius } => (
+                point.length() - radius,
+                point.try_normalize().unwrap_or(Vec2::X),
+            ),
+            ObstacleShape::Rect { half_size } => {
+                let q = point.abs() - *half_size;
+                if q.x > 0. || q.y > 0. {
+                    let outside = point - point.clamp(-*half_size, *half_size);
+                    (outside.length(), outside.normalize())
+                } else if q.x > q.y {
+                    (q.x, Vec2::new(point.x.signum(), 0.))
+                } else {
+                    (q.y, Vec2::new(0., point.y.signum()))
+                }
+            }
+            ObstacleShape::Polygon { points } => {
+                let mut closest = (f32::INFINITY, Vec2::ZERO);
+                let mut inside = false;
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    let edge = b - *a;
+                    let t = ((point - *a).dot(edge) / edge.length_squared()).clamp(0., 1.);
+                    let on_edge = *a + edge * t;
+                    let dist_sq = point.distance_squared(on_edge);
+                    if dist_sq < closest.0 {
+                        closest = (dist_sq, on_edge);
+                    }
+
+                    // Crossing number, counts edges a ray towards +x passes through
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+
+                let sign = if inside { -1. } else { 1. };
+                let normal = (point - closest.1).try_normalize().unwrap_or(Vec2::X) * sign;
+                (closest.0.sqrt() * sign, normal)
+            }
+        }
+    }
+}
+
+/// Moves an [`Obstacle`] through its waypoints in order at `speed` units per second, heading back
+/// to the first one after the last
+#[derive(Component, Clone, Debug)]
+pub struct ObstaclePath {
+    pub waypoints: Vec<Vec2>,
+    pub speed: f32,
+    pub next: usize,
+}
+
+impl ObstaclePath {
+    pub fn new(waypoints: Vec<Vec2>, speed: f32) -> Self {
+        Self {
+            waypoints,
+            speed,
+            next: 0,
+        }
+    }
+}
+
+/// Steering away from every obstacle a boid at `position` is about to run into, looking
+/// `obstacle_lookahead` seconds ahead along `velocity`. On a torus obstacles are seen across the
+/// edges like everything else
+pub fn obstacle_avoidance(
+    position: Vec2,
+    velocity: Vec2,
+    obstacles: &[(&Transform, &Obstacle)],
+    values: &Values,
+    topology: Topology,
+) -> Vec2 {
+    let mut dv = Vec2::ZERO;
+    let margin = values.obstacle_margin.max(f32::EPSILON);
+
+    for (transform, Obstacle(shape)) in obstacles {
+        let mut strongest = (0.0, Vec2::ZERO);
+        // Where the boid is now, halfway there and where it will be
+        for t in [0.0, 0.5, 1.0] {
+            let probe = position + velocity * values.obstacle_lookahead * t;
+            let from_center = topology.offset(transform.translation.xy(), probe);
+            let local = transform.rotation.inverse() * from_center.extend(0.);
+            let (distance, normal) = shape.distance(local.xy());
+            // Closer calls count for more, and the near probes are more urgent than the far one
+            let strength = (margin - distance) / margin * (1.5 - t);
+            if strength > strongest.0 {
+                strongest = (strength, (transform.rotation * normal.extend(0.)).xy());
+            }
+        }
+        dv += strongest.1 * strongest.0;
+    }
+
+    dv * values.obstacle_avoidance_factor
+}
+
+pub fn obstacle_path_system(
+    mut obstacles: Query<(&mut Transform, &mut ObstaclePath)>,
+    time: Res<Time>,
+) {
+    let dt = time.delta_seconds();
+    for (mut transform, mut path) in obstacles.iter_mut() {
+        let Some(&target) = path.waypoints.get(path.next) else {
+            continue;
+        };
+
+        let to_target = target - transform.translation.xy();
+        let step = path.speed * dt;
+        if to_target.length() <= step {
+            transform.translation = target.extend(transform.translation.z);
+            path.next = (path.next + 1) % path.waypoints.len();
+        } else {
+            transform.translation += (to_target.normalize() * step).extend(0.);
+        }
+    }
+}
+
+/// A few pillars, a wall with a gap in it and a wedge that sweeps across the middle
+pub fn demo_obstacles(mut commands: Commands) {
+    for x in [-250., 250.] {
+        commands.spawn((
+            Transform::from_xyz(x, 80., 0.),
+            Obstacle(ObstacleShape::Circle { radius: 30. }),
+        ));
+    }
+
+    for y in [-160., 60.] {
+        commands.spawn((
+            Transform::from_xyz(0., y, 0.),
+            Obstacle(ObstacleShape::Rect {
+                half_size: Vec2::new(10., 60.),
+            }),
+        ));
+    }
+
+    commands.spawn((
+        Transform::from_xyz(-200., -120., 0.),
+        Obstacle(ObstacleShape::Polygon {
+            points: vec![
+                Vec2::new(-30., -25.),
+                Vec2::new(30., -25.),
+                Vec2::new(0., 35.),
+            ],
+        }),
+        ObstaclePath::new(vec![Vec2::new(200., -120.), Vec2::new(-200., -120.)], 45.),
+    ));
+}
@@ -0,0 +1,60 @@
+use crate::boid::{SpatialEntity, Velocity};
+use crate::SimBounds;
+use crate::SimRng;
+use crate::Values;
+use bevy::prelude::*;
+use rand::Rng;
+
+/// A boid sitting on the ground. It doesn't flock or move until the timer runs out
+#[derive(Component)]
+pub struct Perched(pub Timer);
+
+/**
+* @param values: Res<Values> - The values resource
+* @param bounds: Res<SimBounds> - Size of the simulated area
+* @description Perching mode, boids that fly into the ground edge of the bounds land, sit there for
+* a random amount of time between `boid_perch_min_time` and `boid_perch_max_time`, then take off
+* again and rejoin the flock. A torus has no ground, so nobody perches in toroidal mode
+*
+*/
+pub fn perch_system(
+    mut commands: Commands,
+    mut boids: Query<
+        (Entity, &mut Velocity, &mut Transform, Option<&mut Perched>),
+        With<SpatialEntity>,
+    >,
+    mut rng: ResMut<SimRng>,
+    time: Res<Time>,
+    bounds: Res<SimBounds>,
+    values: Res<Values>,
+) {
+    // Same edge velo_system starts turning boids away from
+    let ground = -(bounds.0.y - values.boid_bound_size) / 2.;
+    let perching = values.modes.perching && !values.modes.toroidal;
+
+    for (boid, mut velocity, mut transform, perched) in boids.iter_mut() {
+        match perched {
+            Some(mut perched) => {
+                perched.0.tick(time.delta());
+                // Everyone takes off straight away if perching gets turned off, or the ground
+                // goes away
+                if perched.0.finished() || !perching {
+                    velocity.0 = Vec2::new(rng.0.gen_range(-1.0..1.0), 1.0).normalize()
+                        * values.boid_min_speed;
+                    commands.entity(boid).remove::<Perched>();
+                }
+            }
+            None if perching && transform.translation.y <= ground && velocity.0.y < 0. => {
+                let min = values.boid_perch_min_time.max(0.);
+                let max = values.boid_perch_max_time.max(min);
+                velocity.0 = Vec2::ZERO;
+                transform.translation.y = ground;
+                commands.entity(boid).insert(Perched(Timer::from_seconds(
+                    rng.0.gen_range(min..=max),
+                    TimerMode::Once,
+                )));
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::boid::Velocity;
+use crate::neighbours::Neighbours;
+use crate::store::BoidStore;
+use crate::SimBounds;
+use crate::SimRng;
+use crate::Topology;
+use crate::Values;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A hunter that isn't a boid. Predators don't flock, they pick a target with
+/// `predator_strategy` and fly at it, turning no faster than `predator_turn_rate`. They're kept in
+/// their own KD-Tree so boids can look for them separately from each other
+#[derive(Component, Default)]
+pub struct Predator;
+
+/// How a predator decides what to go after
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PredatorStrategy {
+    /// The closest boid
+    #[default]
+    Nearest,
+    /// The boid with the fewest neighbours within `predator_vision_range`, falling back to the
+    /// closest boid if none are in sight
+    MostIsolated,
+    /// The middle of the densest patch of boids
+    LargestCluster,
+}
+
+#[derive(Bundle)]
+pub struct PredatorBundle {
+    transform: Transform,
+    velocity: Velocity,
+    marker: Predator,
+}
+
+impl PredatorBundle {
+    /// A predator at `position` heading off in a random direction drawn from `rng`
+    pub fn random(rng: &mut impl Rng, position: Vec2, values: &Values) -> Self {
+        let heading = Vec2::from_angle(rng.gen_range(0.0..std::f32::consts::TAU));
+
+        Self {
+            transform: Transform::from_translation(position.extend(0.0)),
+            velocity: Velocity(heading * values.predator_speed),
+            marker: Predator,
+        }
+    }
+}
+
+// Spawns or despawns predators until there are `predator_count` of them
+pub fn predator_population_system(
+    mut commands: Commands,
+    predators: Query<Entity, With<Predator>>,
+    mut rng: ResMut<SimRng>,
+    bounds: Res<SimBounds>,
+    values: Res<Values>,
+) {
+    let target = values.predator_count.max(0) as usize;
+    let current = predators.iter().len();
+
+    for _ in current..target {
+        let position = Vec2::new(
+            rng.0.gen_range(-0.5..0.5) * bounds.0.x,
+            rng.0.gen_range(-0.5..0.5) * bounds.0.y,
+        );
+        commands.spawn(PredatorBundle::random(&mut rng.0, position, &values));
+    }
+
+    for predator in predators.iter().skip(target) {
+        commands.entity(predator).despawn();
+    }
+}
+
+/**
+* @param predators: Query<(&mut Velocity, &Transform), With<Predator>> - Query of all predators
+* @param store: Res<BoidStore> - Every flying boid, the ones predators go after
+* @param neighbours: Res<Neighbours> - The neighbour index over the store
+* @param bounds: Res<SimBounds> - Size of the simulated area
+* @param values: Res<Values> - The values resource
+* @param time: Res<Time> - The fixed clock, turning is limited per second
+* @description Points every predator at its target and turns it towards it, movement_system then
+* moves it like any boid. Predators that stray out of the bounds head back to the middle first
+*
+*/
+pub fn predator_system(
+    mut predators: Query<(&mut Velocity, &Transform), With<Predator>>,
+    store: Res<BoidStore>,
+    neighbours: Res<Neighbours>,
+    bounds: Res<SimBounds>,
+    values: Res<Values>,
+    time: Res<Time>,
+) {
+    let width = (bounds.0.x - values.boid_bound_size) / 2.;
+    let height = (bounds.0.y - values.boid_bound_size) / 2.;
+    let topology = Topology::new(&bounds, &values.modes);
+    let max_turn = values.predator_turn_rate * time.delta_seconds();
+
+    // Only worked out when somebody needs it, it's the same for every predator
+    let cluster = (values.predator_strategy == PredatorStrategy::LargestCluster)
+        .then(|| densest_patch(&store.front.positions, values.boid_vis_range))
+        .flatten();
+
+    for (mut velocity, transform) in predators.iter_mut() {
+        let position = transform.translation.xy();
+
+        // Nearest boid, looking across the edges of a torus too
+        let positions = &store.front.positions;
+        let nearest = || {
+            neighbours
+                .nearest(
+                    positions,
+                    position,
+                    values.predator_vision_range,
+                    topology,
+                    |_| true,
+                )
+                .map(|slot| positions[slot])
+        };
+
+        // There's no out of bounds on a torus
+        let target = if !values.modes.toroidal
+            && (position.x.abs() > width || position.y.abs() > height)
+        {
+            Some(Vec2::ZERO)
+        } else {
+            match values.predator_strategy {
+                PredatorStrategy::Nearest => nearest(),
+                PredatorStrategy::MostIsolated => {
+                    let mut found = vec![];
+                    for image in topology.images(position, values.predator_vision_range) {
+                        neighbours
+                            .index()
+                            .within(image, values.predator_vision_range, &mut found);
+                    }
+                    found
+                        .into_iter()
+                        .map(|slot| {
+                            let dist = topology.offset(position, positions[slot]).length_squared();
+                            (store.front.neighbors[slot], dist.to_bits(), slot)
+                        })
+                        .min()
+                        .map(|(_, _, slot)| positions[slot])
+                        .or_else(nearest)
+                }
+                PredatorStrategy::LargestCluster => cluster,
+            }
+        };
+
+        let heading = velocity.0.try_normalize().unwrap_or(Vec2::X);
+        let desired = target
+            .and_then(|target| topology.offset(position, target).try_normalize())
+            .unwrap_or(heading);
+
+        // Turn towards the target, but only as fast as the turning limit allows
+        let angle = heading.angle_between(desired);
+        let turn = angle.clamp(-max_turn, max_turn);
+        velocity.0 = Vec2::from_angle(turn).rotate(heading) * values.predator_speed;
+    }
+}
+
+// Bins the boids into cells the size of their vision range and returns the center of mass of
+// the busiest 3x3 block of cells
+fn densest_patch(positions: &[Vec2], cell_size: f32) -> Option<Vec2> {
+    let cell_size = cell_size.max(1.);
+    let mut cells: HashMap<IVec2, (usize, Vec2)> = HashMap::default();
+    for &position in positions {
+        let cell = cells
+            .entry((position / cell_size).floor().as_ivec2())
+            .or_default();
+        cell.0 += 1;
+        cell.1 += position;
+    }
+
+    cells
+        .keys()
+        .map(|center| {
+            let mut count = 0;
+            let mut sum = Vec2::ZERO;
+            for x in -1..=1 {
+                for y in -1..=1 {
+                    if let Some((n, s)) = cells.get(&(*center + IVec2::new(x, y))) {
+                        count += n;
+                        sum += *s;
+                    }
+                }
+            }
+            (count, *center, sum / count as f32)
+        })
+        // Ties go to the lowest cell so the choice doesn't depend on hash order
+        .max_by_key(|(count, center, _)| (*count, -center.x, -center.y))
+        .map(|(_, _, centroid)| centroid)
+}
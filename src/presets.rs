@@ -0,0 +1,180 @@
+use crate::Values;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Where presets are shipped and where saved ones end up
+pub const PRESET_DIR: &str = "assets/presets";
+
+// Baked into the binary so they're there on the web too, where there's no directory to read
+const BUILTIN_PRESETS: &[(&str, &str, PresetFormat)] = &[
+    (
+        "murmuration",
+        include_str!("../assets/presets/murmuration.ron"),
+        PresetFormat::Ron,
+    ),
+    (
+        "fish_school",
+        include_str!("../assets/presets/fish_school.ron"),
+        PresetFormat::Ron,
+    ),
+    (
+        "swarm",
+        include_str!("../assets/presets/swarm.toml"),
+        PresetFormat::Toml,
+    ),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresetFormat {
+    Ron,
+    Toml,
+}
+
+impl PresetFormat {
+    /// Picks the format from a file extension, anything without one is RON
+    pub fn from_path(path: &Path) -> Result<Self, PresetError> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ron") | None => Ok(PresetFormat::Ron),
+            Some("toml") => Ok(PresetFormat::Toml),
+            Some(other) => Err(PresetError::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(std::io::Error),
+    Ron(ron::Error),
+    Toml(String),
+    UnknownFormat(String),
+    NotFound(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "{err}"),
+            PresetError::Ron(err) => write!(f, "bad RON: {err}"),
+            PresetError::Toml(err) => write!(f, "bad TOML: {err}"),
+            PresetError::UnknownFormat(ext) => {
+                write!(f, "unknown preset format .{ext}, use .ron or .toml")
+            }
+            PresetError::NotFound(name) => write!(f, "no preset called {name}"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<std::io::Error> for PresetError {
+    fn from(err: std::io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+impl From<ron::Error> for PresetError {
+    fn from(err: ron::Error) -> Self {
+        PresetError::Ron(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for PresetError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        PresetError::Ron(err.code)
+    }
+}
+
+impl From<toml::de::Error> for PresetError {
+    fn from(err: toml::de::Error) -> Self {
+        PresetError::Toml(err.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for PresetError {
+    fn from(err: toml::ser::Error) -> Self {
+        PresetError::Toml(err.to_string())
+    }
+}
+
+/// Reads a preset. Anything the preset leaves out keeps its default value
+pub fn parse_preset(text: &str, format: PresetFormat) -> Result<Values, PresetError> {
+    let mut values: Values = match format {
+        PresetFormat::Ron => ron::from_str(text)?,
+        PresetFormat::Toml => toml::from_str(text)?,
+    };
+    values.update_derived();
+    Ok(values)
+}
+
+pub fn write_preset(values: &Values, format: PresetFormat) -> Result<String, PresetError> {
+    Ok(match format {
+        PresetFormat::Ron => ron::ser::to_string_pretty(values, ron::ser::PrettyConfig::default())?,
+        PresetFormat::Toml => toml::to_string_pretty(values)?,
+    })
+}
+
+pub fn load_preset(path: &Path) -> Result<Values, PresetError> {
+    let format = PresetFormat::from_path(path)?;
+    parse_preset(&std::fs::read_to_string(path)?, format)
+}
+
+pub fn save_preset(path: &Path, values: &Values) -> Result<(), PresetError> {
+    let text = write_preset(values, PresetFormat::from_path(path)?)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+/// Where a preset saved under `name` goes. Names without an extension are saved as RON
+pub fn preset_path(name: &str) -> PathBuf {
+    let path = Path::new(PRESET_DIR).join(name);
+    if path.extension().is_some() {
+        path
+    } else {
+        path.with_extension("ron")
+    }
+}
+
+/// Loads a preset by file path, or by name from the preset directory and then the built in ones.
+/// Saved presets win over built in ones with the same name
+pub fn find_preset(name: &str) -> Result<Values, PresetError> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return load_preset(path);
+    }
+    for ext in ["ron", "toml"] {
+        let path = Path::new(PRESET_DIR).join(name).with_extension(ext);
+        if path.is_file() {
+            return load_preset(&path);
+        }
+    }
+    BUILTIN_PRESETS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .ok_or_else(|| PresetError::NotFound(name.to_string()))
+        .and_then(|(_, text, format)| parse_preset(text, *format))
+}
+
+/// Names of every preset [`find_preset`] can load, sorted
+pub fn preset_names() -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN_PRESETS
+        .iter()
+        .map(|(name, _, _)| name.to_string())
+        .collect();
+    // There's no preset directory on the web, just the built in ones then
+    if let Ok(entries) = std::fs::read_dir(PRESET_DIR) {
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if PresetFormat::from_path(&path).is_err() {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(stem.to_string());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
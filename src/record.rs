@@ -0,0 +1,567 @@
+//! Recording runs to disk and reading them back.
+//!
+//! A recording is a header followed by one frame per simulation tick. The header holds the
+//! [`Values`] the run started with, the world size when the first tick was recorded and the tick
+//! rate, every frame holds the position, velocity and color of every boid. Those are quantized to
+//! integers and stored as the difference to the same boid in the frame before, as zigzag varints,
+//! so a flock that moves smoothly costs a few bytes per boid per tick. Every
+//! [`KEYFRAME_INTERVAL`]th frame stores absolute values and the `Values` again, which is what
+//! makes seeking possible without decoding from the start.
+//!
+//! ```text
+//! header: "BOIDREC" version:u8 width:f32 height:f32 tick_hz:f32 keyframe_interval:uvarint
+//!         values:string
+//! frame:  length:uvarint flags:u8 tick:uvarint [values:string] count:uvarint boid*
+//! boid:   id_delta:uvarint channel_delta:svarint * 7
+//! ```
+//!
+//! Strings are a uvarint length and RON, floats are little endian. The seven channels are x, y
+//! in 1/16ths, vx, vy in 1/4ths (velocities are per second) and hue, saturation and lightness in
+//! 1/1000ths. A recording cut off part way through a frame just loses that frame.
+
+use crate::boid::{SimpleColor, SpatialEntity, Velocity};
+use crate::sim::SimTick;
+use crate::{SimBounds, Values};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 7] = b"BOIDREC";
+const VERSION: u8 = 2;
+
+/// How many frames apart the frames that don't depend on the ones before them are
+pub const KEYFRAME_INTERVAL: u64 = 60;
+
+const FLAG_KEYFRAME: u8 = 1;
+const FLAG_VALUES: u8 = 2;
+
+const POSITION_SCALE: f32 = 16.0;
+const VELOCITY_SCALE: f32 = 4.0;
+const COLOR_SCALE: f32 = 1000.0;
+
+type Channels = [i32; 7];
+
+/// One boid in one frame of a recording. Ids stay the same for as long as the boid lives
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordedBoid {
+    pub id: u32,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub color: Vec3,
+}
+
+impl RecordedBoid {
+    fn quantize(position: Vec2, velocity: Vec2, color: Vec3) -> Channels {
+        let q = |value: f32, scale: f32| (value * scale).round() as i32;
+        [
+            q(position.x, POSITION_SCALE),
+            q(position.y, POSITION_SCALE),
+            q(velocity.x, VELOCITY_SCALE),
+            q(velocity.y, VELOCITY_SCALE),
+            q(color.x, COLOR_SCALE),
+            q(color.y, COLOR_SCALE),
+            q(color.z, COLOR_SCALE),
+        ]
+    }
+
+    fn from_channels(id: u32, c: &Channels) -> Self {
+        let f = |value: i32, scale: f32| value as f32 / scale;
+        Self {
+            id,
+            position: Vec2::new(f(c[0], POSITION_SCALE), f(c[1], POSITION_SCALE)),
+            velocity: Vec2::new(f(c[2], VELOCITY_SCALE), f(c[3], VELOCITY_SCALE)),
+            color: Vec3::new(
+                f(c[4], COLOR_SCALE),
+                f(c[5], COLOR_SCALE),
+                f(c[6], COLOR_SCALE),
+            ),
+        }
+    }
+}
+
+/// Everything recorded for one tick
+#[derive(Clone, Default)]
+pub struct Frame {
+    pub tick: u64,
+    /// Only on keyframes and frames where the settings changed
+    pub values: Option<Values>,
+    /// Sorted by id
+    pub boids: Vec<RecordedBoid>,
+}
+
+/// Writes a recording, one [`Recorder::record`] per tick
+#[derive(Resource)]
+pub struct Recorder {
+    out: BufWriter<File>,
+    tick_hz: f64,
+    ids: HashMap<Entity, u32>,
+    next_id: u32,
+    previous: HashMap<u32, Channels>,
+    previous_values: Values,
+    frames: u64,
+}
+
+impl Recorder {
+    /// Starts a recording of a run starting out with `values`. The header waits for the first
+    /// frame, by then a window has had the chance to set the size of the world
+    pub fn create(path: &Path, values: &Values, tick_hz: f64) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            tick_hz,
+            ids: HashMap::default(),
+            next_id: 0,
+            previous: HashMap::default(),
+            previous_values: *values,
+            frames: 0,
+        })
+    }
+
+    /// Appends a frame with every boid in `boids`, as (entity, position, velocity, color). `bounds`
+    /// is the size of the world, only the one the first frame is recorded in is kept
+    pub fn record(
+        &mut self,
+        tick: u64,
+        values: &Values,
+        bounds: Vec2,
+        boids: impl Iterator<Item = (Entity, Vec2, Vec2, Vec3)>,
+    ) -> io::Result<()> {
+        if self.frames == 0 {
+            self.out.write_all(MAGIC)?;
+            self.out.write_all(&[VERSION])?;
+            self.out.write_all(&bounds.x.to_le_bytes())?;
+            self.out.write_all(&bounds.y.to_le_bytes())?;
+            self.out.write_all(&(self.tick_hz as f32).to_le_bytes())?;
+            write_uvarint(&mut self.out, KEYFRAME_INTERVAL)?;
+            write_values(&mut self.out, &self.previous_values)?;
+        }
+
+        // New boids get their ids in entity order so the same run always writes the same file
+        let mut boids: Vec<_> = boids.collect();
+        boids.sort_unstable_by_key(|(entity, ..)| *entity);
+        let mut boids: Vec<(u32, Channels)> = boids
+            .into_iter()
+            .map(|(entity, position, velocity, color)| {
+                let id = *self.ids.entry(entity).or_insert_with(|| {
+                    self.next_id += 1;
+                    self.next_id - 1
+                });
+                (id, RecordedBoid::quantize(position, velocity, color))
+            })
+            .collect();
+        boids.sort_unstable_by_key(|(id, _)| *id);
+
+        let keyframe = self.frames.is_multiple_of(KEYFRAME_INTERVAL);
+        let values_changed = keyframe || *values != self.previous_values;
+        let mut body = Vec::with_capacity(boids.len() * 8 + 16);
+        body.push(
+            if keyframe { FLAG_KEYFRAME } else { 0 } | if values_changed { FLAG_VALUES } else { 0 },
+        );
+        write_uvarint(&mut body, tick)?;
+        if values_changed {
+            write_values(&mut body, values)?;
+        }
+        write_uvarint(&mut body, boids.len() as u64)?;
+        let mut previous_id = 0;
+        for (id, channels) in &boids {
+            write_uvarint(&mut body, (id - previous_id) as u64)?;
+            previous_id = *id;
+            let base = match self.previous.get(id) {
+                Some(base) if !keyframe => *base,
+                _ => Channels::default(),
+            };
+            for (value, base) in channels.iter().zip(base) {
+                write_svarint(&mut body, value.wrapping_sub(base) as i64)?;
+            }
+        }
+        write_uvarint(&mut self.out, body.len() as u64)?;
+        self.out.write_all(&body)?;
+
+        // Forget boids that are gone, ids are never reused
+        self.previous = boids.into_iter().collect();
+        self.ids.retain(|_, id| self.previous.contains_key(id));
+        self.previous_values = *values;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct FrameIndex {
+    offset: u64,
+    tick: u64,
+    keyframe: bool,
+}
+
+/// Reads a recording back, from any frame in any order
+pub struct Recording {
+    file: BufReader<File>,
+    values: Values,
+    bounds: Vec2,
+    tick_hz: f64,
+    frames: Vec<FrameIndex>,
+    /// The frame `state` holds, if any
+    decoded: Option<usize>,
+    state: Vec<(u32, Channels)>,
+}
+
+impl Recording {
+    /// Opens a recording and finds where every frame starts, without decoding any of them
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 7];
+        file.read_exact(&mut magic)?;
+        let version = read_u8(&mut file)?;
+        if &magic != MAGIC || version != VERSION {
+            return Err(invalid(
+                "not a boids recording, or from a different version",
+            ));
+        }
+        let bounds = Vec2::new(read_f32(&mut file)?, read_f32(&mut file)?);
+        let tick_hz = read_f32(&mut file)? as f64;
+        let _keyframe_interval = read_uvarint(&mut file)?;
+        let values = read_values(&mut file)?;
+
+        let mut frames = Vec::new();
+        let mut offset = file.stream_position()?;
+        let end = file.get_ref().metadata()?.len();
+        while offset < end {
+            let (length, flags, tick) = match read_frame_header(&mut file) {
+                Ok(header) => header,
+                // Cut off while the last frame was being written, the rest are fine
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let start = offset + uvarint_len(length);
+            if start + length > end {
+                break;
+            }
+            frames.push(FrameIndex {
+                offset,
+                tick,
+                keyframe: flags & FLAG_KEYFRAME != 0,
+            });
+            offset = start + length;
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        if frames.first().is_some_and(|frame| !frame.keyframe) {
+            return Err(invalid("recording doesn't start with a keyframe"));
+        }
+
+        Ok(Self {
+            file,
+            values,
+            bounds,
+            tick_hz,
+            frames,
+            decoded: None,
+            state: Vec::new(),
+        })
+    }
+
+    /// The settings the run started with
+    pub fn values(&self) -> Values {
+        self.values
+    }
+
+    /// Size of the world the run was recorded in
+    pub fn bounds(&self) -> Vec2 {
+        self.bounds
+    }
+
+    /// Ticks per second the run was recorded at
+    pub fn tick_hz(&self) -> f64 {
+        self.tick_hz
+    }
+
+    /// Number of frames, one per recorded tick
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Simulation tick frame `index` was recorded on
+    pub fn tick(&self, index: usize) -> u64 {
+        self.frames[index].tick
+    }
+
+    /// Decodes frame `index`. Going forward a frame at a time is cheap, anything else starts over
+    /// from the closest keyframe before it
+    pub fn frame(&mut self, index: usize) -> io::Result<Frame> {
+        if index >= self.frames.len() {
+            return Err(invalid("frame out of range"));
+        }
+        let keyframe = (0..=index)
+            .rev()
+            .find(|&i| self.frames[i].keyframe)
+            .unwrap_or(0);
+        let start = match self.decoded {
+            Some(decoded) if decoded < index && decoded >= keyframe => decoded + 1,
+            _ => keyframe,
+        };
+
+        let mut values = None;
+        for i in start..=index {
+            let frame_values = self.decode(i)?;
+            values = frame_values.or(values);
+        }
+        Ok(Frame {
+            tick: self.frames[index].tick,
+            values,
+            boids: self
+                .state
+                .iter()
+                .map(|(id, channels)| RecordedBoid::from_channels(*id, channels))
+                .collect(),
+        })
+    }
+
+    fn decode(&mut self, index: usize) -> io::Result<Option<Values>> {
+        let file = &mut self.file;
+        file.seek(SeekFrom::Start(self.frames[index].offset))?;
+        let _length = read_uvarint(file)?;
+        let flags = read_u8(file)?;
+        let _tick = read_uvarint(file)?;
+        let values = if flags & FLAG_VALUES != 0 {
+            Some(read_values(file)?)
+        } else {
+            None
+        };
+
+        let keyframe = flags & FLAG_KEYFRAME != 0;
+        let previous: HashMap<u32, Channels> = if keyframe {
+            HashMap::default()
+        } else {
+            self.state.drain(..).collect()
+        };
+        let count = read_uvarint(file)? as usize;
+        self.state.clear();
+        self.state.reserve(count);
+        let mut id = 0u32;
+        for _ in 0..count {
+            id += read_uvarint(file)? as u32;
+            let mut channels = previous.get(&id).copied().unwrap_or_default();
+            for channel in channels.iter_mut() {
+                *channel = channel.wrapping_add(read_svarint(file)? as i32);
+            }
+            self.state.push((id, channels));
+        }
+        self.decoded = Some(index);
+        Ok(values)
+    }
+}
+
+/// Records every tick of the simulation to the [`Recorder`], if there is one
+pub fn record_system(
+    recorder: Option<ResMut<Recorder>>,
+    mut commands: Commands,
+    tick: Res<SimTick>,
+    values: Res<Values>,
+    bounds: Res<SimBounds>,
+    boids: Query<(Entity, &Transform, &Velocity, &SimpleColor), With<SpatialEntity>>,
+) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    // Nothing to record while paused
+    if !tick.is_changed() || tick.0 == 0 {
+        return;
+    }
+
+    let boids = boids.iter().map(|(entity, transform, velocity, color)| {
+        (
+            entity,
+            transform.translation.truncate(),
+            velocity.0,
+            color.0,
+        )
+    });
+    if let Err(err) = recorder.record(tick.0, &values, bounds.0, boids) {
+        error!("recording failed, stopping it: {err}");
+        commands.remove_resource::<Recorder>();
+    }
+}
+
+// The file only gets written in full once the buffer is flushed
+pub fn flush_recorder_system(mut exit: EventReader<AppExit>, recorder: Option<ResMut<Recorder>>) {
+    if exit.read().last().is_none() {
+        return;
+    }
+    if let Some(mut recorder) = recorder {
+        if let Err(err) = recorder.flush() {
+            error!("couldn't finish writing the recording: {err}");
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_uvarint(out: &mut impl Write, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        out.write_all(&[value as u8 | 0x80])?;
+        value >>= 7;
+    }
+    out.write_all(&[value as u8])
+}
+
+fn write_svarint(out: &mut impl Write, value: i64) -> io::Result<()> {
+    write_uvarint(out, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn write_values(out: &mut impl Write, values: &Values) -> io::Result<()> {
+    let text = ron::to_string(values).map_err(|err| invalid(&err.to_string()))?;
+    write_uvarint(out, text.len() as u64)?;
+    out.write_all(text.as_bytes())
+}
+
+// Length, flags and tick of the frame starting where `input` is
+fn read_frame_header(input: &mut impl Read) -> io::Result<(u64, u8, u64)> {
+    let length = read_uvarint(input)?;
+    let flags = read_u8(input)?;
+    let tick = read_uvarint(input)?;
+    Ok((length, flags, tick))
+}
+
+fn uvarint_len(value: u64) -> u64 {
+    (64 - value.leading_zeros() as u64).div_ceil(7).max(1)
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_f32(input: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_uvarint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(input)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn read_svarint(input: &mut impl Read) -> io::Result<i64> {
+    let value = read_uvarint(input)?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+fn read_values(input: &mut impl Read) -> io::Result<Values> {
+    let length = read_uvarint(input)? as usize;
+    let mut text = vec![0; length];
+    input.read_exact(&mut text)?;
+    let text = String::from_utf8(text).map_err(|_| invalid("settings aren't UTF-8"))?;
+    let mut values: Values = ron::from_str(&text).map_err(|err| invalid(&err.to_string()))?;
+    values.update_derived();
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Somewhere to write a recording that no other test is using
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("boids-{}-{name}.boidrec", std::process::id()))
+    }
+
+    // A few boids flying in circles, one of them only around for the middle of the run
+    fn boids(frame: u64) -> Vec<(Entity, Vec2, Vec2, Vec3)> {
+        (0..5u32)
+            .filter(|&i| i != 4 || (30..100).contains(&frame))
+            .map(|i| {
+                let angle = frame as f32 * 0.05 + i as f32;
+                let position = Vec2::from_angle(angle) * (100.0 + i as f32 * 20.0);
+                let velocity = Vec2::from_angle(angle).perp() * 300.0;
+                let color = Vec3::new((frame as f32 * 3.0) % 360.0, 0.5, 0.6);
+                (Entity::from_raw(i), position, velocity, color)
+            })
+            .collect()
+    }
+
+    fn write(path: &Path, frames: u64) {
+        let values = Values::default();
+        let mut recorder = Recorder::create(path, &values, 60.0).unwrap();
+        for frame in 0..frames {
+            recorder
+                .record(
+                    frame + 1,
+                    &values,
+                    Vec2::new(800.0, 500.0),
+                    boids(frame).into_iter(),
+                )
+                .unwrap();
+        }
+        recorder.flush().unwrap();
+    }
+
+    fn assert_frame(recording: &mut Recording, index: usize) {
+        let frame = recording.frame(index).unwrap();
+        let expected = boids(index as u64);
+        assert_eq!(frame.tick, index as u64 + 1);
+        assert_eq!(frame.boids.len(), expected.len());
+        for (boid, (_, position, velocity, color)) in frame.boids.iter().zip(expected) {
+            assert!((boid.position - position).abs().max_element() <= 0.5 / POSITION_SCALE);
+            assert!((boid.velocity - velocity).abs().max_element() <= 0.5 / VELOCITY_SCALE);
+            assert!((boid.color - color).abs().max_element() <= 0.5 / COLOR_SCALE);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        write(&path, 150);
+        let mut recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.len(), 150);
+        assert_eq!(recording.bounds(), Vec2::new(800.0, 500.0));
+        assert_eq!(recording.tick_hz(), 60.0);
+
+        // Forwards across the keyframes, then jumping back to frames between them
+        for index in 0..150 {
+            assert_frame(&mut recording, index);
+        }
+        for index in [97, 61, 3, 140] {
+            assert_frame(&mut recording, index);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let path = temp_path("truncated");
+        // The last frame is a keyframe, big enough to need two bytes for its length
+        write(&path, 61);
+        let bytes = std::fs::read(&path).unwrap();
+        let whole = Recording::open(&path).unwrap();
+        let last = whole.frames[60].offset as usize;
+        assert_ne!(bytes[last] & 0x80, 0);
+
+        // Part way through the last frame's length, and part way through its body
+        for cut in [last + 1, bytes.len() - 3] {
+            std::fs::write(&path, &bytes[..cut]).unwrap();
+            let mut recording = Recording::open(&path).unwrap();
+            assert_eq!(recording.len(), 60);
+            assert_frame(&mut recording, 59);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use crate::boid::*;
+use crate::obstacle::{Obstacle, ObstacleShape};
+use crate::predator::Predator;
+use crate::replay::Replay;
+use crate::CursorPosition;
+use crate::SimBounds;
+use crate::Values;
+use crate::BOUNDS;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::sprite::Mesh2dHandle;
+use bevy::window::PrimaryWindow;
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+/// Draws whatever [`BoidsSimPlugin`](crate::sim::BoidsSimPlugin) is simulating, and feeds the
+/// window size and mouse position back into it. Needs `DefaultPlugins`.
+pub struct BoidsRenderPlugin;
+
+impl Plugin for BoidsRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VisionCone>()
+            .add_systems(Startup, camera_setup)
+            .add_systems(
+                Update,
+                (
+                    attach_boid_mesh_system,
+                    attach_predator_mesh_system,
+                    attach_obstacle_mesh_system,
+                    boid_material_system,
+                    boid_scale_system,
+                    cursor_system,
+                    // A replay keeps the size of the world it was recorded in
+                    bounds_system.run_if(not(resource_exists::<Replay>)),
+                    select_boid_system,
+                    vision_cone_system,
+                ),
+            );
+    }
+}
+
+/// The boid whose vision cone is drawn over the flock, if any. Right click a boid to pick it,
+/// right click anywhere else to stop
+#[derive(Resource, Default)]
+pub struct VisionCone(pub Option<Entity>);
+
+/// Shared mesh every boid is drawn with
+#[derive(Resource)]
+pub struct BoidMesh(Mesh2dHandle);
+
+/// Shared mesh and material every predator is drawn with
+#[derive(Resource)]
+pub struct PredatorMesh(Mesh2dHandle, Handle<ColorMaterial>);
+
+pub fn camera_setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    commands.spawn(Camera2dBundle::default());
+    commands.insert_resource(BoidMesh(Mesh2dHandle(meshes.add(Circle { radius: 4.0 }))));
+    // Pointing along +x, same as a boid with no rotation
+    commands.insert_resource(PredatorMesh(
+        Mesh2dHandle(meshes.add(Triangle2d::new(
+            Vec2::new(8.0, 0.0),
+            Vec2::new(-6.0, 5.0),
+            Vec2::new(-6.0, -5.0),
+        ))),
+        materials.add(Color::srgb(1.0, 0.2, 0.2)),
+    ));
+}
+
+// Boids are spawned by the simulation without anything to draw, give them a mesh and their own
+// material so they can be recolored individually
+pub fn attach_boid_mesh_system(
+    mut commands: Commands,
+    new_boids: Query<(Entity, &SimpleColor), Added<SpatialEntity>>,
+    mesh: Res<BoidMesh>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for (boid, color) in new_boids.iter() {
+        commands.entity(boid).insert((
+            mesh.0.clone(),
+            materials.add(Color::hsl(color.0.x, color.0.y, color.0.z)),
+            GlobalTransform::default(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+pub fn attach_predator_mesh_system(
+    mut commands: Commands,
+    new_predators: Query<Entity, Added<Predator>>,
+    mesh: Res<PredatorMesh>,
+) {
+    for predator in new_predators.iter() {
+        commands.entity(predator).insert((
+            mesh.0.clone(),
+            mesh.1.clone(),
+            GlobalTransform::default(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+pub fn attach_obstacle_mesh_system(
+    mut commands: Commands,
+    new_obstacles: Query<(Entity, &Obstacle), Added<Obstacle>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for (obstacle, Obstacle(shape)) in new_obstacles.iter() {
+        let mesh = match shape {
+            ObstacleShape::Circle { radius } => meshes.add(Circle::new(*radius)),
+            ObstacleShape::Rect { half_size } => meshes.add(Rectangle::from_size(*half_size * 2.)),
+            // Triangle fan from the first point, fine for convex polygons
+            ObstacleShape::Polygon { points } => meshes.add(
+                Mesh::new(
+                    PrimitiveTopology::TriangleList,
+                    RenderAssetUsages::RENDER_WORLD,
+                )
+                .with_inserted_attribute(
+                    Mesh::ATTRIBUTE_POSITION,
+                    points
+                        .iter()
+                        .map(|p| p.extend(0.).to_array())
+                        .collect::<Vec<_>>(),
+                )
+                .with_inserted_indices(Indices::U32(
+                    (1..points.len().saturating_sub(1) as u32)
+                        .flat_map(|i| [0, i, i + 1])
+                        .collect(),
+                )),
+            ),
+        };
+
+        commands.entity(obstacle).insert((
+            Mesh2dHandle(mesh),
+            materials.add(Color::srgb(0.35, 0.35, 0.4)),
+            GlobalTransform::default(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+pub fn boid_material_system(
+    boids: Query<(&SimpleColor, &Handle<ColorMaterial>), Changed<SimpleColor>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for (color, material_handle) in boids.iter() {
+        if let Some(material) = materials.get_mut(material_handle) {
+            material.color = Color::hsl(color.0.x, color.0.y, color.0.z);
+        }
+    }
+}
+
+pub fn boid_scale_system(
+    mut boids: Query<&mut Transform, With<SpatialEntity>>,
+    values: Res<Values>,
+) {
+    if !values.is_changed() {
+        return;
+    }
+
+    let scale = Vec3::splat(values.boid_size);
+    for mut transform in boids.iter_mut() {
+        if transform.scale != scale {
+            transform.scale = scale;
+        }
+    }
+}
+
+pub fn cursor_system(
+    mut commands: Commands,
+    cursor: Option<ResMut<CursorPosition>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    window: Query<&Window, With<PrimaryWindow>>,
+) {
+    let c_world = match (camera.get_single(), window.get_single()) {
+        (Ok((camera, t_camera)), Ok(window)) => window
+            .cursor_position()
+            .and_then(|c_window| camera.viewport_to_world_2d(t_camera, c_window)),
+        _ => None,
+    };
+
+    match (c_world, cursor) {
+        (Some(c_world), Some(mut cursor)) => cursor.0 = c_world,
+        (Some(c_world), None) => commands.insert_resource(CursorPosition(c_world)),
+        (None, Some(_)) => commands.remove_resource::<CursorPosition>(),
+        (None, None) => {}
+    }
+}
+
+// Picks the boid under the cursor on a right click, or none if there isn't one close enough
+pub fn select_boid_system(
+    mouse: Res<ButtonInput<MouseButton>>,
+    cursor: Option<Res<CursorPosition>>,
+    boids: Query<(Entity, &Transform), With<SpatialEntity>>,
+    mut cone: ResMut<VisionCone>,
+) {
+    let Some(cursor) = cursor else {
+        return;
+    };
+    if !mouse.just_pressed(MouseButton::Right) {
+        return;
+    }
+
+    const PICK_RADIUS: f32 = 15.0;
+    cone.0 = boids
+        .iter()
+        .map(|(boid, transform)| (transform.translation.xy().distance(cursor.0), boid))
+        .filter(|(distance, _)| *distance < PICK_RADIUS)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, boid)| boid);
+}
+
+// Outlines what the selected boid can see: the field of view out to its visual range, its
+// protected range, and the blind spot behind it in red. Everything is lined up with its velocity,
+// same as the flocking rules
+pub fn vision_cone_system(
+    mut gizmos: Gizmos,
+    mut cone: ResMut<VisionCone>,
+    boids: Query<(&Transform, &Velocity), With<SpatialEntity>>,
+    values: Res<Values>,
+) {
+    let Some(boid) = cone.0 else {
+        return;
+    };
+    // Died or got turned into something else since it was picked
+    let Ok((transform, velocity)) = boids.get(boid) else {
+        cone.0 = None;
+        return;
+    };
+
+    let position = transform.translation.xy();
+    let heading = velocity.0.y.atan2(velocity.0.x);
+    let fov = values.boid_fov.min(TAU);
+    // Arcs are measured from +y, headings from +x
+    let ahead = heading - FRAC_PI_2;
+    let color = Color::srgba(1.0, 1.0, 1.0, 0.6);
+
+    gizmos
+        .arc_2d(position, ahead, fov, values.boid_vis_range, color)
+        .resolution(64);
+    gizmos.circle_2d(position, values.boid_prot_range, color.with_alpha(0.3));
+    if fov < TAU {
+        for edge in [heading - fov / 2.0, heading + fov / 2.0] {
+            gizmos.line_2d(
+                position,
+                position + Vec2::from_angle(edge) * values.boid_vis_range,
+                color,
+            );
+        }
+    }
+
+    let blind_spot = values.boid_blind_spot.min(TAU);
+    if blind_spot > 0.0 {
+        gizmos.arc_2d(
+            position,
+            ahead + PI,
+            blind_spot,
+            values.boid_vis_range,
+            Color::srgba(1.0, 0.2, 0.2, 0.6),
+        );
+    }
+}
+
+pub fn bounds_system(
+    window: Query<&Window, (With<PrimaryWindow>, Changed<Window>)>,
+    mut bounds: ResMut<SimBounds>,
+) {
+    if let Ok(window) = window.get_single() {
+        bounds.0 = Vec2::new(window.width(), window.height());
+    }
+}
+
+pub fn setup_bounds(mut commands: Commands) {
+    // Create a rectangular border
+    let border_thickness = 2.0;
+
+    // Top border
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::WHITE,
+            custom_size: Some(Vec2::new(BOUNDS.x, border_thickness)),
+            ..default()
+        },
+        transform: Transform::from_translation(Vec3::new(0.0, BOUNDS.y / 2.0, 0.0)),
+        ..default()
+    });
+
+    // Bottom border
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::WHITE,
+            custom_size: Some(Vec2::new(BOUNDS.x, border_thickness)),
+            ..default()
+        },
+        transform: Transform::from_translation(Vec3::new(0.0, -BOUNDS.y / 2.0, 0.0)),
+        ..default()
+    });
+
+    // Left border
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::WHITE,
+            custom_size: Some(Vec2::new(border_thickness, BOUNDS.y)),
+            ..default()
+        },
+        transform: Transform::from_translation(Vec3::new(-BOUNDS.x / 2.0, 0.0, 0.0)),
+        ..default()
+    });
+
+    // Right border
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::WHITE,
+            custom_size: Some(Vec2::new(border_thickness, BOUNDS.y)),
+            ..default()
+        },
+        transform: Transform::from_translation(Vec3::new(BOUNDS.x / 2.0, 0.0, 0.0)),
+        ..default()
+    });
+}
@@ -0,0 +1,213 @@
+use crate::boid::{SimpleColor, SpatialEntity, Velocity};
+use crate::flock::FlockEvent;
+use crate::record::{Frame, Recording};
+use crate::sim::{sim_running, step_system, time_scale_system, SimControl, TICK_HZ};
+use crate::{SimBounds, Values};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_egui::{egui, EguiContexts};
+
+/// Plays a [`Replay`] back instead of simulating, one recorded frame per tick. Takes the place of
+/// [`BoidsSimPlugin`](crate::sim::BoidsSimPlugin), the render plugin and settings UI work on top
+/// of it the same way. Insert the [`Replay`] before the app starts.
+///
+/// Pausing, single steps and the time scale all work like they do for the simulation, the replay
+/// window can also jump to any frame.
+pub struct ReplayPlugin;
+
+/// The recording being played back and how far along it is
+#[derive(Resource)]
+pub struct Replay {
+    recording: Recording,
+    /// Frame that should be on screen
+    pub frame: usize,
+    /// Frame that is on screen
+    shown: Option<usize>,
+    boids: HashMap<u32, Entity>,
+    /// The recorded settings last put into `Values`, so changes made while watching aren't undone
+    /// on every keyframe
+    recorded_values: Option<Values>,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Self {
+        Self {
+            recording,
+            frame: 0,
+            shown: None,
+            boids: HashMap::default(),
+            recorded_values: None,
+        }
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    fn last_frame(&self) -> usize {
+        self.recording.len().saturating_sub(1)
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        let (values, bounds, tick_hz) = app
+            .world()
+            .get_resource::<Replay>()
+            .map(|replay| {
+                let recording = &replay.recording;
+                (recording.values(), recording.bounds(), recording.tick_hz())
+            })
+            .unwrap_or((Values::default(), SimBounds::default().0, TICK_HZ));
+
+        app.insert_resource(values)
+            .insert_resource(SimBounds(bounds))
+            .init_resource::<SimControl>()
+            .insert_resource(Time::<Fixed>::from_hz(tick_hz))
+            // Nothing sends these during a replay, but the flock event log still reads them
+            .add_event::<FlockEvent>()
+            .add_systems(Update, (time_scale_system, replay_ui_system))
+            .add_systems(
+                FixedUpdate,
+                (advance_replay_system.run_if(sim_running), step_system).chain(),
+            )
+            .add_systems(Update, show_frame_system.after(replay_ui_system));
+    }
+}
+
+pub fn advance_replay_system(mut replay: ResMut<Replay>, mut values: ResMut<Values>) {
+    if replay.frame < replay.last_frame() {
+        replay.frame += 1;
+    } else if !values.modes.paused {
+        // Stop at the end instead of looping, so the last frame can be looked at
+        values.modes.paused = true;
+    }
+}
+
+// Moves the boids to wherever they were in the frame that should be on screen, spawning and
+// despawning them as they came and went
+pub fn show_frame_system(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    mut values: ResMut<Values>,
+    mut boids: Query<(&mut Transform, &mut Velocity, &mut SimpleColor), With<SpatialEntity>>,
+) {
+    if replay.recording.is_empty() || replay.shown == Some(replay.frame) {
+        return;
+    }
+
+    let index = replay.frame.min(replay.last_frame());
+    let Frame {
+        values: recorded_values,
+        boids: recorded,
+        ..
+    } = match replay.recording.frame(index) {
+        Ok(frame) => frame,
+        Err(err) => {
+            error!("couldn't read frame {index} of the recording: {err}");
+            replay.frame = replay.shown.unwrap_or(0);
+            return;
+        }
+    };
+    replay.shown = Some(index);
+
+    if let Some(recorded_values) = recorded_values {
+        if replay.recorded_values.as_ref() != Some(&recorded_values) {
+            replay.recorded_values = Some(recorded_values);
+            let paused = values.modes.paused;
+            *values = recorded_values;
+            values.modes.paused = paused;
+        }
+    }
+
+    let mut seen = HashMap::with_capacity(recorded.len());
+    for boid in recorded {
+        let existing = replay.boids.get(&boid.id).and_then(|&entity| {
+            boids
+                .get_mut(entity)
+                .ok()
+                .map(|components| (entity, components))
+        });
+        match existing {
+            Some((entity, (mut transform, mut velocity, mut color))) => {
+                transform.translation = boid.position.extend(transform.translation.z);
+                velocity.0 = boid.velocity;
+                color.set_if_neq(SimpleColor(boid.color));
+                seen.insert(boid.id, entity);
+            }
+            None => {
+                let entity = commands
+                    .spawn((
+                        Transform::from_translation(boid.position.extend(0.))
+                            .with_scale(Vec3::splat(values.boid_size)),
+                        Velocity(boid.velocity),
+                        SimpleColor(boid.color),
+                        SpatialEntity,
+                    ))
+                    .id();
+                seen.insert(boid.id, entity);
+            }
+        }
+    }
+    for (id, entity) in replay.boids.iter() {
+        if !seen.contains_key(id) {
+            commands.entity(*entity).despawn_recursive();
+        }
+    }
+    replay.boids = seen;
+}
+
+pub fn replay_ui_system(
+    mut egui_context: EguiContexts,
+    mut replay: ResMut<Replay>,
+    mut values: ResMut<Values>,
+) {
+    if replay.recording.is_empty() {
+        return;
+    }
+
+    let last = replay.last_frame();
+    let mut frame = replay.frame;
+    let mut paused = values.modes.paused;
+    egui::Window::new("Replay")
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -10.0))
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("|<").clicked() {
+                    frame = 0;
+                }
+                if ui.button("<").clicked() {
+                    frame = frame.saturating_sub(1);
+                }
+                if ui.button(if paused { "Play" } else { "Pause" }).clicked() {
+                    // Playing from the end starts over
+                    if paused && frame == last {
+                        frame = 0;
+                    }
+                    paused = !paused;
+                }
+                if ui.button(">").clicked() {
+                    frame = (frame + 1).min(last);
+                }
+                if ui.button(">|").clicked() {
+                    frame = last;
+                }
+            });
+            ui.spacing_mut().slider_width = 400.0;
+            ui.add(egui::Slider::new(&mut frame, 0..=last).show_value(false));
+            ui.label(format!(
+                "tick {} (frame {} of {})",
+                replay.recording.tick(frame),
+                frame + 1,
+                last + 1
+            ));
+        });
+
+    if frame != replay.frame {
+        replay.frame = frame;
+    }
+    if paused != values.modes.paused {
+        values.modes.paused = paused;
+    }
+}
@@ -0,0 +1,267 @@
+use crate::boid::*;
+use crate::evil::{evil_system, neglect_system, KillCount};
+use crate::flock::{
+    flock_event_log_system, flock_system, flush_flock_event_log_system, tracking_flocks,
+    FlockEvent, Flocks,
+};
+use crate::metrics::{flush_metrics_system, metrics_system};
+use crate::neighbours::{neighbour_index_system, Neighbours};
+use crate::obstacle::obstacle_path_system;
+use crate::perch::perch_system;
+use crate::predator::{predator_population_system, predator_system, Predator};
+use crate::record::{flush_recorder_system, record_system};
+use crate::store::{load_store_system, write_back_system, BoidStore};
+use crate::SimBounds;
+use crate::SimRng;
+use crate::SimSeed;
+use crate::Values;
+use bevy::prelude::*;
+use bevy_spatial::{AutomaticUpdate, SpatialSet, SpatialStructure};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use std::time::Duration;
+
+/// The simulation half of the app: spawning, flocking, velocity and movement. Boids are stepped
+/// in a [`BoidStore`] and written back to their components once per tick. Doesn't touch
+/// cameras, windows or assets so it runs just as well on `MinimalPlugins` as on `DefaultPlugins`.
+///
+/// Cursor chasing only happens while a [`CursorPosition`](crate::CursorPosition) resource is
+/// present, the render plugin keeps it up to date from the mouse. Randomness comes from
+/// [`SimSeed`], insert one up front for a reproducible run. Every tick is written to the
+/// [`Recorder`](crate::record::Recorder) while there is one, and measured for the
+/// [`MetricsExporter`](crate::metrics::MetricsExporter) likewise. Flocks are only tracked with a
+/// [`FlockEventLog`](crate::flock::FlockEventLog) or [`TrackFlocks`](crate::flock::TrackFlocks)
+/// around.
+pub struct BoidsSimPlugin;
+
+/// Every system that steps the simulation, all in [`FixedUpdate`]. Order against it to see a
+/// finished tick. Doesn't run while `Modes::paused` is set, except for single steps
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct SimSet;
+
+/// Ticks per second the simulation runs at unless told otherwise. Everything in [`Values`] is per
+/// second, so changing it only changes how finely the motion is stepped
+pub const TICK_HZ: f64 = 60.0;
+
+/// Playback controls on top of `Modes::paused`
+#[derive(Resource, Clone, Copy, PartialEq)]
+pub struct SimControl {
+    /// Run exactly one tick even though the simulation is paused, cleared once it has
+    pub step: bool,
+    /// Multiplier on how fast simulated time passes, 2 runs the simulation at double speed. Ticks
+    /// stay the same length, there are just more of them each second
+    pub time_scale: f32,
+}
+
+/// Number of ticks the simulation has actually run, pauses don't count
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimTick(pub u64);
+
+/// How long the last run of `flocking_system` took, the bulk of a tick
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct FlockTiming(pub Duration);
+
+impl Default for SimControl {
+    fn default() -> Self {
+        Self {
+            step: false,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl Plugin for BoidsSimPlugin {
+    fn build(&self, app: &mut App) {
+        // Predators are kept in a KD-Tree so boids can look for them directly. It's rebuilt on
+        // every fixed tick (any period shorter than a tick does that), a tree that lags behind
+        // depending on the frame rate would make runs unrepeatable. Boids find each other through
+        // Neighbours instead
+        app.add_plugins(
+            AutomaticUpdate::<Predator>::new()
+                .with_schedule(FixedUpdate)
+                .with_spatial_ds(SpatialStructure::KDTree2)
+                .with_frequency(Duration::from_nanos(1)),
+        )
+        .init_resource::<Values>()
+        .init_resource::<SimBounds>()
+        .init_resource::<SimSeed>()
+        .init_resource::<KillCount>()
+        .init_resource::<HaltonIndex>()
+        .init_resource::<SimControl>()
+        .init_resource::<SimTick>()
+        .init_resource::<FlockTiming>()
+        .init_resource::<Flocks>()
+        .init_resource::<BoidStore>()
+        .init_resource::<Neighbours>()
+        .insert_resource(Time::<Fixed>::from_hz(TICK_HZ))
+        .add_event::<FlockEvent>()
+        .configure_sets(FixedUpdate, SimSet.after(SpatialSet).run_if(sim_running))
+        .add_systems(PreStartup, seed_system)
+        .add_systems(Update, time_scale_system)
+        .add_systems(
+            FixedUpdate,
+            (
+                step_system,
+                record_system,
+                metrics_system,
+                flock_event_log_system,
+            )
+                .after(SimSet),
+        )
+        .add_systems(
+            Last,
+            (
+                flush_recorder_system,
+                flush_metrics_system,
+                flush_flock_event_log_system,
+            ),
+        )
+        .add_systems(Startup, boid_setup)
+        .add_systems(
+            FixedUpdate,
+            (
+                derived_values_system,
+                population_system,
+                predator_population_system,
+                obstacle_path_system,
+                load_store_system,
+                neighbour_index_system,
+                evil_system,
+                flock_system.run_if(tracking_flocks),
+                predator_system,
+                flocking_system,
+                write_back_system,
+                movement_system,
+                perch_system,
+                loneliness_system,
+                neglect_system,
+                tick_system,
+            )
+                .chain()
+                .in_set(SimSet),
+        );
+    }
+}
+
+pub fn sim_running(values: Res<Values>, control: Res<SimControl>) -> bool {
+    !values.modes.paused || control.step
+}
+
+// A single step only gets one tick
+pub fn step_system(mut control: ResMut<SimControl>) {
+    if control.step {
+        control.step = false;
+    }
+}
+
+pub fn tick_system(mut tick: ResMut<SimTick>) {
+    tick.0 += 1;
+}
+
+pub fn time_scale_system(control: Res<SimControl>, mut time: ResMut<Time<Virtual>>) {
+    if control.is_changed() {
+        time.set_relative_speed(control.time_scale.max(0.01));
+    }
+}
+
+pub fn seed_system(mut commands: Commands, seed: Res<SimSeed>) {
+    info!("simulation seed: {}", seed.0);
+    commands.insert_resource(SimRng(SmallRng::seed_from_u64(seed.0)));
+}
+
+// Keeps vis_range_sq and friends in line with whatever changed Values, only writing back when they
+// were actually stale so this doesn't flag Values as changed on every tick
+pub fn derived_values_system(mut values: ResMut<Values>) {
+    if values.is_changed() {
+        let mut updated = *values;
+        if updated.update_derived() {
+            *values = updated;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flock::{FlockId, TrackFlocks};
+    use crate::Modes;
+    use bevy::core::TaskPoolOptions;
+    use bevy::time::TimeUpdateStrategy;
+
+    // Every boid's position and velocity as raw bits, by entity
+    fn run(seed: u64, ticks: usize) -> Vec<(Entity, [u32; 4])> {
+        let mut app = App::new();
+        // Several threads even on one core, so the work really gets split up between them
+        app.add_plugins(MinimalPlugins.set(TaskPoolPlugin {
+            task_pool_options: TaskPoolOptions::with_num_threads(4),
+        }))
+        // Every update is exactly one tick
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / TICK_HZ,
+        )))
+        .add_plugins(BoidsSimPlugin)
+        .insert_resource(SimSeed(seed))
+        // A bit of everything that touches the flock, evil boids show up fast
+        .insert_resource(Values {
+            boid_count: 400,
+            predator_count: 2,
+            boid_evil_time: 0.5,
+            modes: Modes {
+                evil: true,
+                perching: true,
+                ..default()
+            },
+            ..default()
+        });
+        app.finish();
+        app.cleanup();
+        for _ in 0..ticks {
+            app.update();
+        }
+
+        let world = app.world_mut();
+        let mut boids: Vec<_> = world
+            .query_filtered::<(Entity, &Transform, &Velocity), With<SpatialEntity>>()
+            .iter(world)
+            .map(|(entity, transform, velocity)| {
+                let (p, v) = (transform.translation, velocity.0);
+                (entity, [p.x, p.y, v.x, v.y].map(f32::to_bits))
+            })
+            .collect();
+        boids.sort_unstable_by_key(|(entity, ..)| *entity);
+        boids
+    }
+
+    #[test]
+    fn flocks_are_only_tracked_when_asked() {
+        let flock_ids = |track: bool| {
+            let mut app = App::new();
+            app.add_plugins(MinimalPlugins)
+                .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                    1.0 / TICK_HZ,
+                )))
+                .add_plugins(BoidsSimPlugin)
+                .insert_resource(SimSeed(1));
+            if track {
+                app.insert_resource(TrackFlocks);
+            }
+            app.finish();
+            app.cleanup();
+            for _ in 0..10 {
+                app.update();
+            }
+            let world = app.world_mut();
+            world.query::<&FlockId>().iter(world).count()
+        };
+        assert_eq!(flock_ids(false), 0);
+        assert!(flock_ids(true) > 0);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let first = run(5, 300);
+        assert!(!first.is_empty());
+        assert_eq!(first, run(5, 300));
+        assert_ne!(first, run(6, 300));
+    }
+}
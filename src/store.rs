@@ -0,0 +1,206 @@
+use crate::boid::{Acceleration, Neighbors, SimpleColor, SpatialEntity, StartColor, Velocity};
+use crate::evil::Evil;
+use crate::perch::Perched;
+use bevy::prelude::*;
+
+/// One copy of the state of every flying boid, an array per field with a boid in the same slot of
+/// each
+#[derive(Default)]
+pub struct Buffer {
+    pub positions: Vec<Vec2>,
+    pub velocities: Vec<Vec2>,
+    /// The acceleration that brought the boid to this state
+    pub accelerations: Vec<Vec2>,
+    pub colors: Vec<Vec3>,
+    /// How many boids it could see on the way here
+    pub neighbors: Vec<usize>,
+}
+
+/// A run of slots out of a [`Buffer`], for filling in from one thread
+pub struct BufferChunk<'a> {
+    pub positions: &'a mut [Vec2],
+    pub velocities: &'a mut [Vec2],
+    pub accelerations: &'a mut [Vec2],
+    pub colors: &'a mut [Vec3],
+    pub neighbors: &'a mut [usize],
+}
+
+impl Buffer {
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.positions.clear();
+        self.velocities.clear();
+        self.accelerations.clear();
+        self.colors.clear();
+        self.neighbors.clear();
+    }
+
+    // Old contents are left in place, every slot gets overwritten before it's read
+    fn resize(&mut self, len: usize) {
+        self.positions.resize(len, Vec2::ZERO);
+        self.velocities.resize(len, Vec2::ZERO);
+        self.accelerations.resize(len, Vec2::ZERO);
+        self.colors.resize(len, Vec3::ZERO);
+        self.neighbors.resize(len, 0);
+    }
+
+    /// Splits the buffer into runs of `size` slots, in order
+    pub fn chunks_mut(&mut self, size: usize) -> impl Iterator<Item = BufferChunk<'_>> {
+        self.positions
+            .chunks_mut(size)
+            .zip(self.velocities.chunks_mut(size))
+            .zip(self.accelerations.chunks_mut(size))
+            .zip(self.colors.chunks_mut(size))
+            .zip(self.neighbors.chunks_mut(size))
+            .map(
+                |((((positions, velocities), accelerations), colors), neighbors)| BufferChunk {
+                    positions,
+                    velocities,
+                    accelerations,
+                    colors,
+                    neighbors,
+                },
+            )
+    }
+}
+
+/// Every flying boid laid out as flat arrays for the flocking step. `front` is loaded from the
+/// boids' components at the start of the tick and is what every system looking for other boids
+/// reads them from, along with [`Neighbours`](crate::neighbours::Neighbours). `flocking_system`
+/// writes the next tick into `back` and swaps the two, and the new `front` goes back into the
+/// components in one pass.
+///
+/// Slots follow query order, which is the same every run, so nothing needs sorting to stay
+/// repeatable.
+#[derive(Resource, Default)]
+pub struct BoidStore {
+    entities: Vec<Entity>,
+    /// Slot of every boid in the store by `Entity::index`, stale entries fail the check in `slot`
+    slots: Vec<u32>,
+    pub front: Buffer,
+    pub back: Buffer,
+    pub start_colors: Vec<Vec3>,
+    pub evil: Vec<bool>,
+    /// Steering from outside the flock this tick, an evil boid's pursuit. Zeroed on load and
+    /// filled in by evil_system
+    pub steering: Vec<Vec2>,
+}
+
+impl BoidStore {
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    /// Where `entity` is in the buffers, if it's in the store at all
+    pub fn slot(&self, entity: Entity) -> Option<usize> {
+        let slot = *self.slots.get(entity.index() as usize)? as usize;
+        (self.entities.get(slot) == Some(&entity)).then_some(slot)
+    }
+
+    /// Makes the tick just written into `back` the current one
+    pub fn swap(&mut self) {
+        std::mem::swap(&mut self.front, &mut self.back);
+    }
+}
+
+/// Everything `load_store_system` reads off a boid
+pub type StoreQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Transform,
+        &'static Velocity,
+        &'static Acceleration,
+        &'static SimpleColor,
+        &'static StartColor,
+        &'static Neighbors,
+        Has<Evil>,
+    ),
+    (With<SpatialEntity>, Without<Perched>),
+>;
+
+// Copies every flying boid into the front buffer, perched boids sit the tick out
+pub fn load_store_system(mut store: ResMut<BoidStore>, boids: StoreQuery) {
+    let store = &mut *store;
+    store.entities.clear();
+    store.front.clear();
+    store.start_colors.clear();
+    store.evil.clear();
+    store.steering.clear();
+
+    for (entity, transform, velocity, acceleration, color, start_color, neighbors, evil) in
+        boids.iter()
+    {
+        let index = entity.index() as usize;
+        if index >= store.slots.len() {
+            store.slots.resize(index + 1, u32::MAX);
+        }
+        store.slots[index] = store.entities.len() as u32;
+        store.entities.push(entity);
+
+        store.front.positions.push(transform.translation.xy());
+        store.front.velocities.push(velocity.0);
+        store.front.accelerations.push(acceleration.0);
+        store.front.colors.push(color.0);
+        store.front.neighbors.push(neighbors.0);
+        store.start_colors.push(start_color.0);
+        store.evil.push(evil);
+        store.steering.push(Vec2::ZERO);
+    }
+
+    let len = store.len();
+    store.back.resize(len);
+}
+
+/// Everything `write_back_system` writes to
+pub type WriteBackQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut Transform,
+        &'static mut Velocity,
+        &'static mut Acceleration,
+        &'static mut SimpleColor,
+        &'static mut Neighbors,
+    ),
+    (With<SpatialEntity>, Without<Perched>),
+>;
+
+// Puts the tick flocking_system just finished back into the boids' components. Colors and
+// neighbour counts are only touched when they change, so nothing downstream thinks every boid
+// changed color on every tick
+pub fn write_back_system(store: Res<BoidStore>, mut boids: WriteBackQuery) {
+    let state = &store.front;
+    boids.par_iter_mut().for_each(
+        |(entity, mut transform, mut velocity, mut acceleration, mut color, mut neighbors)| {
+            // Wasn't flying when the store was loaded, so there's nothing to write
+            let Some(slot) = store.slot(entity) else {
+                return;
+            };
+            let v = state.velocities[slot];
+            transform.translation = state.positions[slot].extend(transform.translation.z);
+            transform.rotation = Quat::from_rotation_z(v.y.atan2(v.x));
+            velocity.0 = v;
+            acceleration.0 = state.accelerations[slot];
+            color.set_if_neq(SimpleColor(state.colors[slot]));
+            neighbors.set_if_neq(Neighbors(state.neighbors[slot]));
+        },
+    );
+}
@@ -0,0 +1,516 @@
+use crate::boid::{Integrator, Kernel};
+use crate::evil::KillCount;
+use crate::flock::{FlockEvent, FlockSummary};
+use crate::neighbours::NeighbourBackend;
+use crate::predator::PredatorStrategy;
+use crate::presets;
+use crate::sim::SimControl;
+use crate::Values;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use std::collections::VecDeque;
+
+// Everything is edited on a copy and only written back when something actually changed, so the
+// panel being open doesn't flag Values as changed every frame
+pub fn ui_system(
+    mut egui_context: EguiContexts,
+    mut values: ResMut<Values>,
+    mut control: ResMut<SimControl>,
+    mut presets: Local<PresetPanel>,
+    // Not there during a replay, the recording doesn't have them
+    kills: Option<Res<KillCount>>,
+) {
+    let ctx = egui_context.ctx_mut();
+    let mut edited = *values;
+    let mut edited_control = *control;
+
+    egui::Window::new("Settings")
+        .resizable(true)
+        .collapsible(true)
+        .default_open(true)
+        .vscroll(true)
+        .show(ctx, |ui| {
+            ui.label("Application Settings");
+            playback_settings(ui, &mut edited, &mut edited_control);
+            preset_settings(ui, &mut edited, &mut presets);
+            flock_settings(ui, &mut edited);
+            mode_settings(ui, &mut edited);
+            color_settings(ui, &mut edited);
+            perch_settings(ui, &mut edited);
+            evil_settings(ui, &mut edited, kills.as_deref().map(|kills| kills.0));
+            predator_settings(ui, &mut edited);
+            obstacle_settings(ui, &mut edited);
+        });
+
+    if edited_control != *control {
+        *control = edited_control;
+    }
+
+    if edited != *values {
+        // Don't let the speed limits cross over
+        if edited.boid_min_speed != values.boid_min_speed {
+            edited.boid_max_speed = edited.boid_max_speed.max(edited.boid_min_speed);
+        } else {
+            edited.boid_min_speed = edited.boid_min_speed.min(edited.boid_max_speed);
+        }
+        edited.boid_perch_max_time = edited.boid_perch_max_time.max(edited.boid_perch_min_time);
+        edited.update_derived();
+        *values = edited;
+    }
+}
+
+// Space pauses, period steps a paused simulation by one tick, plus and minus double and halve the
+// time scale and 0 puts it back to normal
+pub fn keyboard_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut egui_context: EguiContexts,
+    mut values: ResMut<Values>,
+    mut control: ResMut<SimControl>,
+) {
+    // Typing into a text box shouldn't pause anything
+    if egui_context.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Space) {
+        values.modes.paused = !values.modes.paused;
+    }
+    if keys.just_pressed(KeyCode::Period) && values.modes.paused {
+        control.step = true;
+    }
+    if keys.any_just_pressed([KeyCode::Equal, KeyCode::NumpadAdd]) {
+        control.time_scale = (control.time_scale * 2.0).min(MAX_TIME_SCALE);
+    }
+    if keys.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        control.time_scale = (control.time_scale / 2.0).max(MIN_TIME_SCALE);
+    }
+    if keys.any_just_pressed([KeyCode::Digit0, KeyCode::Numpad0]) {
+        control.time_scale = 1.0;
+    }
+}
+
+/// Most events the flock event log keeps
+const FLOCK_LOG_LENGTH: usize = 200;
+
+/// What the flock event log remembers between frames
+#[derive(Default)]
+pub struct FlockLog {
+    entries: VecDeque<FlockEvent>,
+    /// Births and deaths of small flocks happen all the time, so they're hidden unless asked for
+    births_and_deaths: bool,
+}
+
+// Newest flock events first
+pub fn flock_log_system(
+    mut egui_context: EguiContexts,
+    mut events: EventReader<FlockEvent>,
+    mut log: Local<FlockLog>,
+) {
+    for event in events.read() {
+        log.entries.push_front(event.clone());
+    }
+    log.entries.truncate(FLOCK_LOG_LENGTH);
+
+    egui::Window::new("Flock events")
+        .resizable(true)
+        .collapsible(true)
+        .default_open(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut log.births_and_deaths, "Births and deaths");
+                if ui.button("Clear").clicked() {
+                    log.entries.clear();
+                }
+            });
+            ui.separator();
+            let births_and_deaths = log.births_and_deaths;
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for event in log.entries.iter().filter(|event| {
+                    births_and_deaths
+                        || matches!(event, FlockEvent::Split { .. } | FlockEvent::Merge { .. })
+                }) {
+                    ui.label(describe_flock_event(event));
+                }
+            });
+        });
+}
+
+fn describe_flock_event(event: &FlockEvent) -> String {
+    let flock = |flock: &FlockSummary| format!("#{} ({})", flock.id.0, flock.size);
+    let flocks = |flocks: &[FlockSummary]| flocks.iter().map(flock).collect::<Vec<_>>().join(", ");
+    let at = |flock: &FlockSummary| format!("at {:.0}, {:.0}", flock.centroid.x, flock.centroid.y);
+    match event {
+        FlockEvent::Birth { tick, flock: f } => format!("{tick}: {} formed {}", flock(f), at(f)),
+        FlockEvent::Death { tick, flock: f } => format!("{tick}: {} broke up {}", flock(f), at(f)),
+        FlockEvent::Split { tick, from, into } => {
+            format!(
+                "{tick}: {} split into {} {}",
+                flock(from),
+                flocks(into),
+                at(from)
+            )
+        }
+        FlockEvent::Merge { tick, from, into } => {
+            format!(
+                "{tick}: {} merged into {} {}",
+                flocks(from),
+                flock(into),
+                at(into)
+            )
+        }
+    }
+}
+
+const MIN_TIME_SCALE: f32 = 1.0 / 16.0;
+const MAX_TIME_SCALE: f32 = 16.0;
+
+fn playback_settings(ui: &mut egui::Ui, values: &mut Values, control: &mut SimControl) {
+    ui.horizontal(|ui| {
+        let label = if values.modes.paused {
+            "Resume"
+        } else {
+            "Pause"
+        };
+        if ui.button(label).on_hover_text("Space").clicked() {
+            values.modes.paused = !values.modes.paused;
+        }
+        if ui
+            .add_enabled(values.modes.paused, egui::Button::new("Step"))
+            .on_hover_text("Period")
+            .clicked()
+        {
+            control.step = true;
+        }
+    });
+    ui.add(
+        egui::Slider::new(&mut control.time_scale, MIN_TIME_SCALE..=MAX_TIME_SCALE)
+            .logarithmic(true)
+            .suffix("x")
+            .text("Time scale"),
+    )
+    .on_hover_text("+ / - / 0");
+}
+
+/// What the preset section remembers between frames
+#[derive(Default)]
+pub struct PresetPanel {
+    names: Vec<String>,
+    selected: String,
+    // Only native builds can save
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    save_name: String,
+    status: String,
+}
+
+fn preset_settings(ui: &mut egui::Ui, values: &mut Values, panel: &mut PresetPanel) {
+    if panel.names.is_empty() {
+        panel.names = presets::preset_names();
+    }
+    egui::CollapsingHeader::new("Presets").show(ui, |ui| {
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("preset")
+                .selected_text(panel.selected.as_str())
+                .show_ui(ui, |ui| {
+                    for name in &panel.names {
+                        ui.selectable_value(&mut panel.selected, name.clone(), name);
+                    }
+                });
+            if ui
+                .add_enabled(!panel.selected.is_empty(), egui::Button::new("Load"))
+                .clicked()
+            {
+                panel.status = match presets::find_preset(&panel.selected) {
+                    Ok(preset) => {
+                        *values = preset;
+                        format!("Loaded {}", panel.selected)
+                    }
+                    Err(err) => err.to_string(),
+                };
+            }
+        });
+
+        // Nowhere to save to on the web
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut panel.save_name).hint_text("name"));
+            if ui
+                .add_enabled(!panel.save_name.is_empty(), egui::Button::new("Save"))
+                .on_hover_text("Saved as RON unless the name ends in .toml")
+                .clicked()
+            {
+                let path = presets::preset_path(&panel.save_name);
+                panel.status = match presets::save_preset(&path, values) {
+                    Ok(()) => {
+                        panel.names = presets::preset_names();
+                        format!("Saved {}", path.display())
+                    }
+                    Err(err) => err.to_string(),
+                };
+            }
+        });
+
+        if !panel.status.is_empty() {
+            ui.label(&panel.status);
+        }
+    });
+}
+
+fn flock_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Flock")
+        .default_open(true)
+        .show(ui, |ui| {
+            ui.add(
+                egui::Slider::new(&mut values.boid_count, 0..=10_000)
+                    .logarithmic(true)
+                    .text("Number of Boids"),
+            );
+            ui.add(egui::Slider::new(&mut values.boid_size, 0.1..=3.0).text("Boid size"));
+            ui.add(egui::Slider::new(&mut values.boid_speed, 0.0..=1200.0).text("Spawn speed"));
+
+            ui.separator();
+            ui.add(egui::Slider::new(&mut values.boid_vis_range, 1.0..=200.0).text("Visual range"));
+            ui.add(
+                egui::Slider::new(&mut values.boid_prot_range, 1.0..=100.0).text("Protected range"),
+            );
+            let mut fov = values.boid_fov.to_degrees();
+            if ui
+                .add(
+                    egui::Slider::new(&mut fov, 0.0..=360.0)
+                        .suffix("°")
+                        .text("Field of view"),
+                )
+                .changed()
+            {
+                values.boid_fov = fov.to_radians();
+            }
+            let mut blind_spot = values.boid_blind_spot.to_degrees();
+            if ui
+                .add(
+                    egui::Slider::new(&mut blind_spot, 0.0..=360.0)
+                        .suffix("°")
+                        .text("Blind spot"),
+                )
+                .changed()
+            {
+                values.boid_blind_spot = blind_spot.to_radians();
+            }
+            ui.add(
+                egui::Slider::new(&mut values.boid_peripheral_weight, 0.0..=1.0)
+                    .text("Peripheral weight"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.max_neighbors, 1..=500)
+                    .logarithmic(true)
+                    .text("Max neighbours"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.flock_min_size, 1..=100)
+                    .logarithmic(true)
+                    .text("Smallest flock"),
+            );
+
+            ui.separator();
+            ui.add(
+                egui::Slider::new(&mut values.boid_centering_factor, 0.0..=36.0)
+                    .logarithmic(true)
+                    .text("Centering"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.boid_avoidance_factor, 0.0..=1800.0)
+                    .logarithmic(true)
+                    .text("Avoidance"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.boid_matching_factor, 0.0..=30.0)
+                    .logarithmic(true)
+                    .text("Matching"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.boid_mouse_chase_factor, 0.0..=36.0)
+                    .logarithmic(true)
+                    .text("Mouse chase"),
+            );
+
+            ui.separator();
+            ui.add(egui::Slider::new(&mut values.boid_min_speed, 0.0..=1800.0).text("Min speed"));
+            ui.add(egui::Slider::new(&mut values.boid_max_speed, 0.0..=1800.0).text("Max speed"));
+            ui.add(
+                egui::Slider::new(&mut values.boid_turn_factor, 0.0..=7200.0).text("Turn factor"),
+            );
+            ui.add(
+                egui::Slider::new(&mut values.boid_bound_size, 0.0..=300.0).text("Border margin"),
+            );
+            egui::ComboBox::from_label("Integrator")
+                .selected_text(format!("{:?}", values.integrator))
+                .show_ui(ui, |ui| {
+                    for integrator in [
+                        Integrator::Euler,
+                        Integrator::SemiImplicitEuler,
+                        Integrator::Verlet,
+                    ] {
+                        ui.selectable_value(
+                            &mut values.integrator,
+                            integrator,
+                            format!("{integrator:?}"),
+                        );
+                    }
+                });
+            for (label, kernel) in [
+                ("Separation kernel", &mut values.separation_kernel),
+                ("Alignment kernel", &mut values.alignment_kernel),
+                ("Cohesion kernel", &mut values.cohesion_kernel),
+            ] {
+                egui::ComboBox::from_label(label)
+                    .selected_text(format!("{kernel:?}"))
+                    .show_ui(ui, |ui| {
+                        for option in [
+                            Kernel::Uniform,
+                            Kernel::Linear,
+                            Kernel::InverseSquare,
+                            Kernel::Gaussian,
+                            Kernel::Topological,
+                        ] {
+                            ui.selectable_value(kernel, option, format!("{option:?}"));
+                        }
+                    });
+            }
+            ui.add(
+                egui::Slider::new(&mut values.boid_topological_neighbors, 1..=50)
+                    .text("Topological neighbours"),
+            );
+            egui::ComboBox::from_label("Neighbour index")
+                .selected_text(format!("{:?}", values.neighbour_backend))
+                .show_ui(ui, |ui| {
+                    for backend in [NeighbourBackend::KdTree, NeighbourBackend::Grid] {
+                        ui.selectable_value(
+                            &mut values.neighbour_backend,
+                            backend,
+                            format!("{backend:?}"),
+                        );
+                    }
+                });
+        });
+}
+
+fn mode_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Modes")
+        .default_open(true)
+        .show(ui, |ui| {
+            let modes = &mut values.modes;
+            ui.checkbox(&mut modes.paused, "Paused");
+            ui.checkbox(&mut modes.mouse_predator, "Mouse is a predator");
+            ui.checkbox(&mut modes.color_mode, "Color blending");
+            ui.checkbox(&mut modes.color_flocking, "Color flocking");
+            ui.checkbox(&mut modes.perching, "Perching");
+            ui.checkbox(&mut modes.toroidal, "Toroidal world");
+            ui.checkbox(&mut modes.evil, "Evil mode");
+        });
+}
+
+fn color_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Color").show(ui, |ui| {
+        ui.add(
+            egui::Slider::new(&mut values.boid_color_blend_rate, 0.0..=60.0)
+                .logarithmic(true)
+                .text("Blend rate"),
+        );
+        ui.add(
+            egui::Slider::new(&mut values.boid_color_revert_rate, 0.0..=60.0)
+                .logarithmic(true)
+                .text("Revert rate"),
+        );
+        ui.add(
+            egui::Slider::new(&mut values.boid_lonely_time, 0.0..=60.0)
+                .suffix(" s")
+                .text("Recolor when alone for"),
+        );
+        ui.add(egui::Slider::new(&mut values.boid_color_affinity, 0.0..=1.0).text("Affinity"));
+        ui.add(
+            egui::Slider::new(&mut values.boid_color_repulsion, 0.0..=36.0)
+                .logarithmic(true)
+                .text("Repulsion"),
+        );
+    });
+}
+
+fn perch_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Perching").show(ui, |ui| {
+        ui.add(
+            egui::Slider::new(&mut values.boid_perch_min_time, 0.0..=30.0)
+                .suffix(" s")
+                .text("Shortest perch"),
+        );
+        ui.add(
+            egui::Slider::new(&mut values.boid_perch_max_time, 0.0..=30.0)
+                .suffix(" s")
+                .text("Longest perch"),
+        );
+    });
+}
+
+fn evil_settings(ui: &mut egui::Ui, values: &mut Values, kills: Option<u32>) {
+    egui::CollapsingHeader::new("Evil").show(ui, |ui| {
+        if let Some(kills) = kills {
+            ui.label(format!("Boids killed: {kills}"));
+        }
+        ui.add(
+            egui::Slider::new(&mut values.boid_evil_time, 0.0..=60.0)
+                .suffix(" s")
+                .text("Turn evil when alone for"),
+        );
+        ui.add(egui::Slider::new(&mut values.boid_pursuit_factor, 0.0..=30.0).text("Pursuit"));
+        ui.add(egui::Slider::new(&mut values.boid_flee_factor, 0.0..=1800.0).text("Flee"));
+        ui.add(egui::Slider::new(&mut values.boid_kill_radius, 0.0..=30.0).text("Kill radius"));
+        ui.add(
+            egui::Slider::new(&mut values.boid_kill_cooldown, 0.0..=30.0)
+                .suffix(" s")
+                .text("Kill cooldown"),
+        );
+    });
+}
+
+fn predator_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Predators").show(ui, |ui| {
+        ui.add(egui::Slider::new(&mut values.predator_count, 0..=20).text("Predators"));
+        ui.add(egui::Slider::new(&mut values.predator_speed, 0.0..=1800.0).text("Speed"));
+        ui.add(
+            egui::Slider::new(&mut values.predator_turn_rate, 0.0..=30.0)
+                .suffix(" rad/s")
+                .text("Turn rate"),
+        );
+        ui.add(
+            egui::Slider::new(&mut values.predator_vision_range, 0.0..=500.0).text("Vision range"),
+        );
+        ui.add(egui::Slider::new(&mut values.boid_scatter_factor, 0.0..=7200.0).text("Scatter"));
+        egui::ComboBox::from_label("Target")
+            .selected_text(format!("{:?}", values.predator_strategy))
+            .show_ui(ui, |ui| {
+                for strategy in [
+                    PredatorStrategy::Nearest,
+                    PredatorStrategy::MostIsolated,
+                    PredatorStrategy::LargestCluster,
+                ] {
+                    ui.selectable_value(
+                        &mut values.predator_strategy,
+                        strategy,
+                        format!("{strategy:?}"),
+                    );
+                }
+            });
+    });
+}
+
+fn obstacle_settings(ui: &mut egui::Ui, values: &mut Values) {
+    egui::CollapsingHeader::new("Obstacles").show(ui, |ui| {
+        ui.add(
+            egui::Slider::new(&mut values.obstacle_lookahead, 0.0..=0.5)
+                .suffix(" s")
+                .text("Look ahead"),
+        );
+        ui.add(egui::Slider::new(&mut values.obstacle_margin, 0.0..=100.0).text("Margin"));
+        ui.add(
+            egui::Slider::new(&mut values.obstacle_avoidance_factor, 0.0..=18000.0)
+                .text("Avoidance"),
+        );
+    });
+}